@@ -0,0 +1,799 @@
+use crate::error::{Error, Result};
+use crate::log::{apply_event, log_path, read_events};
+use crate::sync::{self, get_device, LINES_HEADER};
+use crate::{now, score, Cache, Entry, Format, LinesBackup, Pin, SavedValue, CACHE_VERSION};
+use clap::ValueEnum;
+use miniserde::json::{self, Number, Value};
+use miniserde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::create_dir_all;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// Layout of a cache file on disk, with lines encoded by `encode_key`
+#[derive(Serialize, Deserialize)]
+struct CacheFile {
+    version: i64,
+    /// missing from caches written before the mode was recorded
+    mode: Option<SavedValue>,
+    entries: HashMap<String, Entry>,
+    /// missing from caches written before lines could be pinned
+    pinned: Option<HashMap<String, Pin>>,
+    blocked: Option<Vec<String>>,
+    /// missing from caches written before saves were logged
+    last_event: Option<u64>,
+    trimmed: Option<u64>,
+    /// missing from caches written before unlogged changes were tracked
+    unlogged: Option<bool>,
+}
+
+/// Layout used before entries were versioned, when only count and last save were kept
+#[derive(Deserialize)]
+struct EntryV1 {
+    count: i64,
+    timestamp: i64,
+}
+
+/// Smallest bare number of an unversioned cache taken for a timestamp,
+/// no line gets picked a billion times
+const LEGACY_TIMESTAMP_MIN: i64 = 1_000_000_000;
+
+/// Marks keys stored as hexadecimal bytes
+const HEX_KEY_PREFIX: char = '\0';
+
+/// JSON keys have to be strings, so lines which are not valid UTF-8 are stored as their bytes
+/// in hexadecimal after a NUL, as are lines starting with a NUL to keep the encoding reversible
+pub(crate) fn encode_key(key: &[u8]) -> String {
+    match std::str::from_utf8(key) {
+        Ok(key) if !key.starts_with(HEX_KEY_PREFIX) => key.to_string(),
+        _ => std::iter::once(HEX_KEY_PREFIX.to_string())
+            .chain(key.iter().map(|b| format!("{:02x}", b)))
+            .collect(),
+    }
+}
+
+pub(crate) fn decode_key(key: &str) -> Vec<u8> {
+    let hex = match key.strip_prefix(HEX_KEY_PREFIX) {
+        Some(hex) if hex.len() % 2 == 0 => hex,
+        _ => return key.as_bytes().to_vec(),
+    };
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+        .collect::<std::result::Result<Vec<u8>, _>>()
+        .unwrap_or_else(|_| key.as_bytes().to_vec())
+}
+
+/// Path of a file living next to the cache, named after it
+pub(crate) fn sibling_path(cache_file_path: &Path, suffix: &str) -> PathBuf {
+    let mut path = cache_file_path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+/// Writes the contents to a temporary file synced and then renamed over the old file,
+/// so a run killed halfway never leaves a truncated one behind
+pub(crate) fn write_atomically(path: &Path, contents: &[u8]) -> Result<()> {
+    let tmp_file_path = sibling_path(path, &format!(".tmp-{}", std::process::id()));
+    let write = || -> std::io::Result<()> {
+        let mut file = File::create(&tmp_file_path)?;
+        file.write_all(contents)?;
+        file.sync_all()?;
+        std::fs::rename(&tmp_file_path, path)
+    };
+    write().map_err(|e| {
+        let _ = std::fs::remove_file(&tmp_file_path);
+        e.into()
+    })
+}
+
+/// Name of an enum value as written on the command line, which caches store it as
+pub(crate) fn value_name<T: ValueEnum>(value: &T) -> Vec<u8> {
+    value
+        .to_possible_value()
+        .map(|value| value.get_name().as_bytes().to_vec())
+        .unwrap_or_default()
+}
+
+pub(crate) fn from_value_name<T: ValueEnum>(name: &[u8]) -> Option<T> {
+    T::from_str(std::str::from_utf8(name).ok()?, false).ok()
+}
+
+/// Writes the cache as JSON
+pub(crate) fn write_cache(cache_file_path: &Path, cache: &Cache) -> Result<()> {
+    let mut blocked: Vec<String> = cache.blocked.iter().map(|key| encode_key(key)).collect();
+    blocked.sort();
+    let cache_file = CacheFile {
+        version: CACHE_VERSION,
+        mode: cache.mode.clone(),
+        entries: cache
+            .entries
+            .iter()
+            .map(|(key, entry)| (encode_key(key), entry.clone()))
+            .collect(),
+        pinned: Some(
+            cache
+                .pinned
+                .iter()
+                .map(|(key, pin)| (encode_key(key), *pin))
+                .collect(),
+        ),
+        blocked: Some(blocked),
+        last_event: Some(cache.last_event),
+        trimmed: Some(cache.trimmed),
+        unlogged: Some(cache.unlogged),
+    };
+    write_atomically(cache_file_path, json::to_string(&cache_file).as_bytes())
+}
+
+/// The first caches map each line to a bare number, which was either a count or a
+/// timestamp depending on the value mode used to save it
+fn legacy_entry(value: i64) -> Entry {
+    if value >= LEGACY_TIMESTAMP_MIN {
+        Entry {
+            first_seen: value,
+            last_used: value,
+            ..Entry::new()
+        }
+    } else {
+        Entry {
+            count: value,
+            ..Entry::new()
+        }
+    }
+}
+
+fn number_as_i64(number: &Number) -> i64 {
+    match *number {
+        Number::U64(n) => n as i64,
+        Number::I64(n) => n,
+        Number::F64(n) => n as i64,
+    }
+}
+
+/// Converts an unversioned cache, where values are either bare numbers or
+/// count and timestamp pairs, to entries. Caches holding nothing but timestamps were
+/// saved in timestamp mode, which is recorded so that they keep sorting by them
+fn migrate_unversioned(values: HashMap<String, Value>) -> Option<Cache> {
+    let mut lines_backup = HashMap::new();
+    let mut timestamps = !values.is_empty();
+    for (key, value) in values {
+        let entry = match value {
+            Value::Number(n) => {
+                let value = number_as_i64(&n);
+                timestamps &= value >= LEGACY_TIMESTAMP_MIN;
+                legacy_entry(value)
+            }
+            value => {
+                timestamps = false;
+                let v1: EntryV1 = json::from_str(&json::to_string(&value)).ok()?;
+                Entry {
+                    first_seen: v1.timestamp,
+                    last_used: v1.timestamp,
+                    count: v1.count,
+                    ..Entry::new()
+                }
+            }
+        };
+        lines_backup.insert(decode_key(&key), entry);
+    }
+    let mode = timestamps.then_some(SavedValue::Timestamp);
+    let saved_value = mode.clone().unwrap_or_default();
+    for entry in lines_backup.values_mut() {
+        entry.score = score(&saved_value, entry, entry.last_used);
+    }
+    Some(Cache {
+        mode,
+        unlogged: !lines_backup.is_empty(),
+        entries: lines_backup,
+        ..Cache::default()
+    })
+}
+
+/// Loads a cache, telling whether it had to be migrated from an older layout,
+/// or nothing if the file cannot be parsed
+fn load_cache(cache_file_path: &Path) -> Result<Option<(Cache, bool)>> {
+    let mut file = File::open(cache_file_path)?;
+    let mut contents = String::new();
+    if file.read_to_string(&mut contents).is_err() {
+        return Ok(None);
+    }
+    parse_cache(cache_file_path, &contents)
+}
+
+/// Parses the contents of a cache file of any layout, as `load_cache` does
+pub(crate) fn parse_cache(
+    cache_file_path: &Path,
+    content_str: &str,
+) -> Result<Option<(Cache, bool)>> {
+    let values: HashMap<String, Value> = match json::from_str(content_str) {
+        Ok(values) => values,
+        Err(_) => return Ok(None),
+    };
+    match (values.get("version"), values.get("entries")) {
+        (Some(Value::Number(version)), Some(Value::Object(_))) => {
+            let version = number_as_i64(version);
+            if version > CACHE_VERSION {
+                return Err(Error::UnsupportedVersion {
+                    path: cache_file_path.to_path_buf(),
+                    version,
+                });
+            }
+            let cache_file: Option<CacheFile> = json::from_str(content_str).ok();
+            Ok(cache_file.map(|cache_file| {
+                // entries saved before saves were logged are not in the log
+                let unlogged = cache_file
+                    .unlogged
+                    .unwrap_or(cache_file.last_event.is_none() && !cache_file.entries.is_empty());
+                let cache = Cache {
+                    mode: cache_file.mode,
+                    entries: cache_file
+                        .entries
+                        .into_iter()
+                        .map(|(key, entry)| (decode_key(&key), entry))
+                        .collect(),
+                    pinned: cache_file
+                        .pinned
+                        .unwrap_or_default()
+                        .into_iter()
+                        .map(|(key, pin)| (decode_key(&key), pin))
+                        .collect(),
+                    blocked: cache_file
+                        .blocked
+                        .unwrap_or_default()
+                        .iter()
+                        .map(|key| decode_key(key))
+                        .collect(),
+                    last_event: cache_file.last_event.unwrap_or(0),
+                    trimmed: cache_file.trimmed.unwrap_or(0),
+                    unlogged,
+                    ..Cache::default()
+                };
+                (cache, false)
+            }))
+        }
+        _ => Ok(migrate_unversioned(values).map(|cache| (cache, true))),
+    }
+}
+
+/// Moves the caches kept in the old directory to the new one, unless it already exists.
+/// Directories on different file systems are copied file by file
+fn migrate_dir(old_dir: &Path, new_dir: &Path) -> Result<()> {
+    if new_dir.exists() || !old_dir.is_dir() {
+        return Ok(());
+    }
+    if let Some(parent) = new_dir.parent() {
+        create_dir_all(parent)?;
+    }
+    if std::fs::rename(old_dir, new_dir).is_ok() || new_dir.exists() {
+        return Ok(());
+    }
+    let tmp_dir = sibling_path(new_dir, &format!(".tmp-{}", std::process::id()));
+    create_dir_all(&tmp_dir)?;
+    for file in std::fs::read_dir(old_dir)? {
+        let file = file?;
+        if file.file_type()?.is_file() {
+            std::fs::copy(file.path(), tmp_dir.join(file.file_name()))?;
+        }
+    }
+    if std::fs::rename(&tmp_dir, new_dir).is_err() {
+        std::fs::remove_dir_all(&tmp_dir)?;
+    }
+    std::fs::remove_dir_all(old_dir)?;
+    Ok(())
+}
+
+/// Directory caches are kept in: `BAUS_DIR` if set, otherwise `baus` in the state directory,
+/// where caches kept in the cache directory by older versions are moved to
+pub fn get_cache_dir() -> Result<PathBuf> {
+    if let Some(dir) = std::env::var_os("BAUS_DIR") {
+        return Ok(PathBuf::from(dir));
+    }
+    let state_dir = dirs::state_dir()
+        .or_else(dirs::data_local_dir)
+        .ok_or(Error::NoCacheDir)?;
+    let dir = state_dir.join("baus");
+    if let Some(cache_dir) = dirs::cache_dir() {
+        migrate_dir(&cache_dir.join("baus"), &dir)?;
+    }
+    Ok(dir)
+}
+
+/// Lock, event log, temporary, corrupt and SQLite journal files kept next to caches
+/// are not caches themselves
+fn is_sibling_file(file_name: &str) -> bool {
+    [".lock", ".log", "-journal", "-wal", "-shm"]
+        .iter()
+        .any(|suffix| file_name.ends_with(suffix))
+        || file_name.contains(".log-")
+        || file_name.contains(".tmp-")
+        || file_name.contains(".corrupt-")
+}
+
+/// Names are paths relative to the cache directory, each `/` separated part being a namespace,
+/// which must not leave it nor clash with the files kept next to caches
+fn validate_name(name: &str) -> Result<()> {
+    let invalid = |reason: &str| {
+        Err(Error::InvalidName {
+            name: name.to_string(),
+            reason: reason.to_string(),
+        })
+    };
+    for part in name.split('/') {
+        if part.is_empty() {
+            return invalid("namespaces cannot be empty");
+        }
+        if part.starts_with('.') {
+            return invalid("names cannot start with a dot");
+        }
+        if part.contains(['\\', '\0']) || (cfg!(windows) && part.contains(':')) {
+            return invalid("names cannot contain backslashes, colons or NUL");
+        }
+        if is_sibling_file(part) {
+            return invalid(
+                "names cannot end like lock, log or journal files nor contain .log-, .tmp- or .corrupt-",
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Path of the cache with that name in the directory
+pub fn cache_file_path_in(dir: &Path, name: &str) -> Result<PathBuf> {
+    validate_name(name)?;
+    Ok(name
+        .split('/')
+        .fold(dir.to_path_buf(), |path, part| path.join(part)))
+}
+
+pub fn get_cache_file_path(name: &str) -> Result<PathBuf> {
+    let dir = get_cache_dir()?;
+    create_dir_all(&dir)?;
+    cache_file_path_in(&dir, name)
+}
+
+/// What is listed about each cache of a directory
+#[derive(Clone, Debug, PartialEq)]
+pub struct CacheInfo {
+    /// namespaces separated by `/`
+    pub name: String,
+    /// nothing when the cache cannot be parsed
+    pub entries: Option<usize>,
+    /// unix time of the last change
+    pub modified: i64,
+}
+
+fn find_caches(dir: &Path, namespace: &str, caches: &mut Vec<CacheInfo>) -> Result<()> {
+    for file in std::fs::read_dir(dir)? {
+        let file = file?;
+        let file_name = file.file_name().to_string_lossy().into_owned();
+        if file_name.starts_with('.') || is_sibling_file(&file_name) {
+            continue;
+        }
+        let name = format!("{}{}", namespace, file_name);
+        if file.file_type()?.is_dir() {
+            find_caches(&file.path(), &format!("{}/", name), caches)?;
+            continue;
+        }
+        let modified = file
+            .metadata()?
+            .modified()?
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs() as i64);
+        let format = get_format(&file.path())?;
+        let cache = read_snapshot(&file.path(), &get_device()).ok();
+        let entries = cache.map(|mut cache| {
+            for event in
+                read_events(&log_path(&file.path(), format, &get_device())).unwrap_or_default()
+            {
+                apply_event(&mut cache, &event);
+            }
+            cache.entries.len()
+        });
+        caches.push(CacheInfo {
+            name,
+            entries,
+            modified,
+        });
+    }
+    Ok(())
+}
+
+/// Every cache in the directory and its namespaces, ordered by name,
+/// without locking them as they are only ever replaced whole
+pub fn list_caches(dir: &Path) -> Result<Vec<CacheInfo>> {
+    let mut caches = Vec::new();
+    if dir.is_dir() {
+        find_caches(dir, "", &mut caches)?;
+    }
+    caches.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(caches)
+}
+
+/// Takes an exclusive advisory lock on the cache, held until the returned file is dropped,
+/// so that concurrent runs do not lose each other's updates
+pub fn lock_cache(cache_file_path: &Path) -> Result<File> {
+    let lock_file = File::options()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(sibling_path(cache_file_path, ".lock"))?;
+    lock_file.lock()?;
+    Ok(lock_file)
+}
+
+/// SQLite databases start with this header
+const SQLITE_HEADER: &[u8] = b"SQLite format 3\0";
+
+/// Tells SQLite databases from JSON caches, missing caches being created as JSON
+pub fn get_format(cache_file_path: &Path) -> Result<Format> {
+    let mut header = Vec::new();
+    match File::open(cache_file_path) {
+        Ok(file) => file
+            .take(SQLITE_HEADER.len() as u64)
+            .read_to_end(&mut header)?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Format::Json),
+        Err(e) => return Err(e.into()),
+    };
+    match &header[..] {
+        SQLITE_HEADER => Ok(Format::Sqlite),
+        header if header.starts_with(LINES_HEADER) => Ok(Format::Lines),
+        _ => Ok(Format::Json),
+    }
+}
+
+#[cfg(feature = "sqlite")]
+fn load_sqlite(cache_file_path: &Path) -> Result<Cache> {
+    crate::sqlite::load(cache_file_path)
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn load_sqlite(cache_file_path: &Path) -> Result<Cache> {
+    Err(Error::NoSqlite(cache_file_path.to_path_buf()))
+}
+
+#[cfg(feature = "sqlite")]
+fn read_sqlite(cache_file_path: &Path) -> Result<Cache> {
+    crate::sqlite::load_read_only(cache_file_path)
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn read_sqlite(cache_file_path: &Path) -> Result<Cache> {
+    Err(Error::NoSqlite(cache_file_path.to_path_buf()))
+}
+
+#[cfg(feature = "sqlite")]
+fn write_sqlite(cache_file_path: &Path, saved: &Cache, cache: &Cache) -> Result<()> {
+    crate::sqlite::write(cache_file_path, saved, cache)
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn write_sqlite(cache_file_path: &Path, _saved: &Cache, _cache: &Cache) -> Result<()> {
+    Err(Error::NoSqlite(cache_file_path.to_path_buf()))
+}
+
+/// Writes the cache in its format, SQLite caches only getting what changed since they were saved
+pub(crate) fn save_cache(
+    cache_file_path: &Path,
+    format: Format,
+    saved: &Cache,
+    cache: &Cache,
+    device: &str,
+) -> Result<()> {
+    match format {
+        Format::Json => write_cache(cache_file_path, cache),
+        Format::Sqlite => write_sqlite(cache_file_path, saved, cache),
+        Format::Lines => sync::write(cache_file_path, cache, device),
+    }
+}
+
+/// Rewrites the whole cache in another format
+pub(crate) fn convert_cache(
+    cache_file_path: &Path,
+    cache: &Cache,
+    format: Format,
+    device: &str,
+) -> Result<()> {
+    if format == Format::Sqlite && cfg!(not(feature = "sqlite")) {
+        return Err(Error::NoSqlite(cache_file_path.to_path_buf()));
+    }
+    match format {
+        Format::Json => write_cache(cache_file_path, cache),
+        Format::Lines => sync::write(cache_file_path, cache, device),
+        Format::Sqlite => {
+            let tmp_file_path =
+                sibling_path(cache_file_path, &format!(".tmp-{}", std::process::id()));
+            let _ = std::fs::remove_file(&tmp_file_path);
+            write_sqlite(&tmp_file_path, &Cache::default(), cache)
+                .and_then(|_| Ok(std::fs::rename(&tmp_file_path, cache_file_path)?))
+                .inspect_err(|_| {
+                    let _ = std::fs::remove_file(&tmp_file_path);
+                })
+        }
+    }
+}
+
+/// A cache file which could not be parsed, moved out of the way for an empty cache to take
+/// its place
+#[derive(Clone, Debug, PartialEq)]
+pub struct SetAside {
+    pub path: PathBuf,
+    /// where the file was moved to
+    pub moved_to: PathBuf,
+}
+
+/// Loads the cache file in whichever format it is stored, without the saves of the event log
+/// it was not compacted with, telling where it was moved to if it had to be set aside
+pub(crate) fn load_snapshot(
+    cache_file_path: &Path,
+    device: &str,
+) -> Result<(Cache, Option<SetAside>)> {
+    match get_format(cache_file_path)? {
+        Format::Json => get_json_cache(cache_file_path),
+        Format::Sqlite => Ok((load_sqlite(cache_file_path)?, None)),
+        Format::Lines => get_lines_cache(cache_file_path, device),
+    }
+}
+
+/// Loads the cache file like `load_snapshot` but without writing anything: a missing file
+/// is an empty cache, older layouts are only migrated in memory and a file which cannot be
+/// parsed is an error instead of being set aside
+fn read_snapshot(cache_file_path: &Path, device: &str) -> Result<Cache> {
+    let cache = match get_format(cache_file_path)? {
+        Format::Json => match load_cache(cache_file_path) {
+            Err(Error::Io(e)) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(Cache::default())
+            }
+            loaded => loaded?.map(|(cache, _)| cache),
+        },
+        Format::Sqlite => Some(read_sqlite(cache_file_path)?),
+        Format::Lines => sync::parse(cache_file_path, &std::fs::read(cache_file_path)?, device)?,
+    };
+    cache.ok_or_else(|| Error::Corrupt(cache_file_path.to_path_buf()))
+}
+
+/// Loads the cache along with the saves logged since it was last compacted, without locking
+/// it nor writing to it, migrating and setting aside being left to `Store::open_path`
+pub fn get_cache(cache_file_path: &Path) -> Result<Cache> {
+    let device = get_device();
+    let mut cache = read_snapshot(cache_file_path, &device)?;
+    let format = get_format(cache_file_path)?;
+    for event in read_events(&log_path(cache_file_path, format, &device))? {
+        apply_event(&mut cache, &event);
+    }
+    Ok(cache)
+}
+
+/// Loads the JSON cache, creating it if needed, migrating it from older layouts
+/// and setting it aside to start over if it cannot be parsed
+fn get_json_cache(cache_file_path: &Path) -> Result<(Cache, Option<SetAside>)> {
+    let cache = Cache::default();
+    if !cache_file_path.exists() {
+        write_cache(cache_file_path, &cache)?;
+    }
+    match load_cache(cache_file_path)? {
+        Some((cache, migrated)) => {
+            if migrated {
+                write_cache(cache_file_path, &cache)?;
+            }
+            Ok((cache, None))
+        }
+        None => {
+            let set_aside = set_aside(cache_file_path)?;
+            write_cache(cache_file_path, &cache)?;
+            Ok((cache, Some(set_aside)))
+        }
+    }
+}
+
+/// Moves a cache which does not parse out of the way, so an empty one can take its place
+fn set_aside(cache_file_path: &Path) -> Result<SetAside> {
+    let corrupt_file_path = sibling_path(cache_file_path, &format!(".corrupt-{}", now()));
+    std::fs::rename(cache_file_path, &corrupt_file_path)?;
+    Ok(SetAside {
+        path: cache_file_path.to_path_buf(),
+        moved_to: corrupt_file_path,
+    })
+}
+
+/// Loads a cache in the lines format, with the entries of every device added up
+fn get_lines_cache(cache_file_path: &Path, device: &str) -> Result<(Cache, Option<SetAside>)> {
+    let contents = std::fs::read(cache_file_path)?;
+    match sync::parse(cache_file_path, &contents, device)? {
+        Some(cache) => Ok((cache, None)),
+        None => {
+            let cache = Cache::default();
+            let set_aside = set_aside(cache_file_path)?;
+            sync::write(cache_file_path, &cache, device)?;
+            Ok((cache, Some(set_aside)))
+        }
+    }
+}
+
+pub fn get_lines_backup(cache_file_path: &Path) -> Result<LinesBackup> {
+    Ok(get_cache(cache_file_path)?.entries)
+}
+
+#[cfg(test)]
+pub(crate) fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("baus-test-{}-{}", name, std::process::id()));
+    create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn test_migrate_unversioned() {
+    let values: HashMap<String, Value> =
+        json::from_str(r#"{"horse":2,"hamster":{"count":1,"timestamp":42},"cat":1660000000}"#)
+            .unwrap();
+    let cache = migrate_unversioned(values).unwrap();
+    let scored = |count, last_used, score| Entry {
+        score,
+        ..crate::entry(count, last_used)
+    };
+    assert_eq!(cache.mode, None);
+    assert_eq!(cache.entries.get(&b"horse"[..]), Some(&scored(2, 0, 2.0)));
+    assert_eq!(
+        cache.entries.get(&b"hamster"[..]),
+        Some(&scored(1, 42, 1.0))
+    );
+    assert_eq!(
+        cache.entries.get(&b"cat"[..]),
+        Some(&scored(0, 1660000000, 0.0))
+    );
+}
+
+#[test]
+fn test_migrated_timestamps_sort() {
+    let dir = test_dir("migrate-timestamps");
+    let cache_file_path = dir.join("animals");
+    std::fs::write(
+        &cache_file_path,
+        r#"{"old":1600000000,"new":1700000000,"mid":1650000000}"#,
+    )
+    .unwrap();
+    let cache = get_cache(&cache_file_path).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(cache.mode, Some(SavedValue::Timestamp));
+    assert_eq!(cache.entries.get(&b"new"[..]).unwrap().score, 1700000000.0);
+    let sorted = crate::get_asc_sorted_lines(
+        &cache.mode.unwrap(),
+        crate::lines(&["new", "old", "mid"]),
+        &cache.entries,
+        &crate::KeyOptions::default(),
+        None,
+    )
+    .unwrap();
+    assert_eq!(sorted, crate::lines(&["old", "mid", "new"]));
+}
+
+#[test]
+fn test_key_encoding() {
+    for key in [&b"horse"[..], b"ham\xffster", b"\0horse", b"", b"\0"] {
+        assert_eq!(decode_key(&encode_key(key)), key);
+    }
+    assert_eq!(encode_key(b"horse"), "horse");
+    assert_eq!(encode_key(b"h\xff"), "\x0068ff");
+}
+
+#[test]
+fn test_cache_settings_are_kept() {
+    let dir = test_dir("pins");
+    let cache_file_path = dir.join("animals");
+    std::fs::write(&cache_file_path, r#"{"version":2,"entries":{}}"#).unwrap();
+    let mut cache = get_cache(&cache_file_path).unwrap();
+    cache.pinned.insert(b"horse".to_vec(), Pin::Bottom);
+    cache.blocked.insert(b"h\xff".to_vec());
+    cache.mode = Some(SavedValue::Frecency);
+    write_cache(&cache_file_path, &cache).unwrap();
+    let saved = get_cache(&cache_file_path).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(saved, cache);
+}
+
+#[test]
+fn test_migrate_dir() {
+    let dir = test_dir("migrate");
+    let (old_dir, new_dir) = (dir.join("cache/baus"), dir.join("state/baus"));
+    create_dir_all(&old_dir).unwrap();
+    std::fs::write(old_dir.join("animals"), "{}").unwrap();
+    migrate_dir(&old_dir, &new_dir).unwrap();
+    let moved = std::fs::read_to_string(new_dir.join("animals")).unwrap();
+    let old_dir_exists = old_dir.exists();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(moved, "{}");
+    assert!(!old_dir_exists);
+}
+
+#[test]
+fn test_validate_name() {
+    for name in ["animals", "git/branches", "a.b", "h\u{f6}rse"] {
+        assert!(validate_name(name).is_ok(), "{}", name);
+    }
+    for name in [
+        "",
+        "../.bashrc",
+        "git/../../x",
+        "/etc/passwd",
+        "git/",
+        ".hidden",
+        "a\\b",
+        "animals.lock",
+        "animals.tmp-1",
+        "git/x.corrupt-1",
+    ] {
+        assert!(
+            matches!(validate_name(name), Err(Error::InvalidName { .. })),
+            "{}",
+            name
+        );
+    }
+}
+
+#[test]
+fn test_list_caches() {
+    let dir = test_dir("list-caches");
+    let animals = cache_file_path_in(&dir, "animals").unwrap();
+    let branches = cache_file_path_in(&dir, "git/branches").unwrap();
+    create_dir_all(branches.parent().unwrap()).unwrap();
+    let mut cache = Cache::default();
+    cache.entries.insert(b"horse".to_vec(), Entry::new());
+    write_cache(&animals, &cache).unwrap();
+    write_cache(&branches, &Cache::default()).unwrap();
+    lock_cache(&animals).unwrap();
+    std::fs::write(dir.join("broken"), "{").unwrap();
+    std::fs::write(dir.join("broken.corrupt-1"), "{").unwrap();
+    let caches = list_caches(&dir).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    let caches: Vec<(&str, Option<usize>)> = caches
+        .iter()
+        .map(|cache| (cache.name.as_str(), cache.entries))
+        .collect();
+    assert_eq!(
+        caches,
+        Vec::from([
+            ("animals", Some(1)),
+            ("broken", None),
+            ("git/branches", Some(0))
+        ])
+    );
+}
+
+#[test]
+fn test_get_cache_is_read_only() {
+    let dir = test_dir("read-only");
+    let missing = get_cache(&dir.join("missing")).unwrap();
+    std::fs::write(dir.join("unversioned"), r#"{"horse":2}"#).unwrap();
+    let migrated = get_cache(&dir.join("unversioned")).unwrap();
+    std::fs::write(dir.join("corrupt"), r#"{"version":2,"entries":{"hor"#).unwrap();
+    let corrupt = get_cache(&dir.join("corrupt"));
+    let mut files: Vec<_> = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|file| file.unwrap().file_name())
+        .collect();
+    files.sort();
+    let unversioned = std::fs::read_to_string(dir.join("unversioned")).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert!(missing.entries.is_empty());
+    assert_eq!(migrated.entries.get(&b"horse"[..]).unwrap().count, 2);
+    assert!(matches!(corrupt, Err(Error::Corrupt(path)) if path == dir.join("corrupt")));
+    assert_eq!(files, ["corrupt", "unversioned"]);
+    assert_eq!(unversioned, r#"{"horse":2}"#);
+}
+
+#[test]
+fn test_corrupt_cache_is_set_aside() {
+    let dir = test_dir("corrupt");
+    let cache_file_path = dir.join("animals");
+    std::fs::write(&cache_file_path, r#"{"version":2,"entries":{"hor"#).unwrap();
+    let (cache, set_aside) = load_snapshot(&cache_file_path, "").unwrap();
+    let corrupt = std::fs::read_to_string(&set_aside.as_ref().unwrap().moved_to).unwrap();
+    let files = std::fs::read_dir(&dir).unwrap().count();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert!(cache.entries.is_empty());
+    assert_eq!(set_aside.unwrap().path, cache_file_path);
+    assert_eq!(corrupt, r#"{"version":2,"entries":{"hor"#);
+    assert_eq!(files, 2);
+}
@@ -0,0 +1,679 @@
+use crate::{
+    cache_file_path_in, get_cache_dir, get_cache_file_path, AgingOptions, Blocked, Eviction,
+    ExchangeFormat, Format, History, KeyExtractor, KeyOptions, LimitOptions, ListFormat, Merge,
+    Normalizer, Pin, Result, SavedValue, Settings, SortOptions, DEFAULT_MAX_LOG_EVENTS, NEWLINE,
+    NUL,
+};
+use clap::Parser;
+use clap::Subcommand;
+use clap::ValueEnum;
+use std::ffi::{OsStr, OsString};
+use std::path::PathBuf;
+
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None)]
+pub struct Args {
+    /// Name of the sort to backup, namespaces like `git/branches` being kept in subdirectories
+    #[clap(value_parser, required_unless_present_any = &["caches", "legacy-name"])]
+    pub name: Option<String>,
+
+    /// list every cache with its number of entries and the unix time it last changed
+    #[clap(long, value_parser, conflicts_with_all = &["name", "file"])]
+    pub caches: bool,
+
+    /// directory to keep caches in, instead of BAUS_DIR or the state directory
+    #[clap(long, value_parser, global = true, value_name = "DIR")]
+    pub cache_dir: Option<PathBuf>,
+
+    /// file the cache is kept in, instead of the one named after it in the cache directory
+    #[clap(long, value_parser, global = true, conflicts_with = "cache-dir")]
+    pub file: Option<PathBuf>,
+
+    /// lines are ended by NUL instead of newline, on input and output
+    #[clap(short = '0', long, value_parser, global = true)]
+    pub null: bool,
+
+    #[clap(flatten)]
+    pub key: KeyArgs,
+
+    #[clap(flatten)]
+    pub aging: AgingArgs,
+
+    #[clap(flatten)]
+    pub limit: LimitArgs,
+
+    #[clap(flatten)]
+    pub legacy: LegacyArgs,
+
+    #[clap(subcommand)]
+    pub action: Option<Action>,
+}
+
+/// Actions of the first baus, chosen with `--action`
+#[derive(ValueEnum, Clone, Debug)]
+pub enum LegacyAction {
+    Sort,
+    Save,
+}
+
+/// Flags of the first baus, hidden from the help and mapped onto the positional name
+/// and the subcommands by `Args::with_legacy_flags` so that older scripts keep working
+#[derive(clap::Args, Debug, Default)]
+pub struct LegacyArgs {
+    #[clap(
+        short = 'n',
+        long = "name",
+        value_parser,
+        hide = true,
+        conflicts_with = "name"
+    )]
+    pub legacy_name: Option<String>,
+
+    #[clap(short = 'a', long = "action", value_parser, hide = true)]
+    pub legacy_action: Option<LegacyAction>,
+
+    #[clap(
+        short = 'v',
+        long = "value",
+        value_parser,
+        hide = true,
+        requires = "legacy-action"
+    )]
+    pub legacy_value: Option<SavedValue>,
+
+    #[clap(
+        short = 'd',
+        long = "desc",
+        value_parser,
+        hide = true,
+        requires = "legacy-action"
+    )]
+    pub legacy_desc: bool,
+
+    #[clap(
+        short = 'c',
+        long = "cleanup",
+        value_parser,
+        hide = true,
+        requires = "legacy-action"
+    )]
+    pub legacy_cleanup: bool,
+}
+
+impl Args {
+    /// Where caches are kept
+    pub fn cache_dir(&self) -> Result<PathBuf> {
+        match &self.cache_dir {
+            Some(dir) => Ok(dir.clone()),
+            None => get_cache_dir(),
+        }
+    }
+
+    /// Where the cache with that name is kept, from the most specific option given
+    pub fn cache_file_path(&self, name: &str) -> Result<PathBuf> {
+        match (&self.file, &self.cache_dir) {
+            (Some(file), _) => Ok(file.clone()),
+            (None, Some(dir)) => cache_file_path_in(dir, name),
+            (None, None) => get_cache_file_path(name),
+        }
+    }
+
+    /// Takes the name and action given with the flags of the first baus, as in
+    /// `baus --name animals --action sort --value count --desc`, when none is given otherwise
+    pub fn with_legacy_flags(mut self) -> Args {
+        let legacy = std::mem::take(&mut self.legacy);
+        self.name = self.name.or(legacy.legacy_name);
+        if self.action.is_none() {
+            self.action = legacy.legacy_action.map(|action| match action {
+                LegacyAction::Sort => Action::Sort {
+                    value: legacy.legacy_value,
+                    sort: SortArgs {
+                        desc: legacy.legacy_desc,
+                        cleanup: legacy.legacy_cleanup,
+                        ..SortArgs::default()
+                    },
+                    explain: false,
+                },
+                LegacyAction::Save => Action::Save {
+                    value: legacy.legacy_value,
+                    save: SaveArgs::default(),
+                },
+            });
+        }
+        self
+    }
+
+    pub fn delimiter(&self) -> u8 {
+        if self.null {
+            NUL
+        } else {
+            NEWLINE
+        }
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Action {
+    /// Print standard input lines ordered by their saved value
+    Sort {
+        /// Value to sort by
+        #[clap(short = 'v', long = "mode", alias = "value", value_parser)]
+        value: Option<SavedValue>,
+
+        #[clap(flatten)]
+        sort: SortArgs,
+
+        /// print the value of each line before it
+        #[clap(short, long, value_parser)]
+        explain: bool,
+    },
+    /// Save standard input lines and print them back
+    Save {
+        /// Value to save
+        #[clap(short = 'v', long = "mode", alias = "value", value_parser)]
+        value: Option<SavedValue>,
+
+        #[clap(flatten)]
+        save: SaveArgs,
+    },
+    /// Sort standard input, let a picker choose among the lines and save the choice
+    Pick {
+        /// Value to sort by and save
+        #[clap(short = 'v', long = "mode", alias = "value", value_parser)]
+        value: Option<SavedValue>,
+
+        #[clap(flatten)]
+        sort: SortArgs,
+
+        #[clap(flatten)]
+        save: SaveArgs,
+
+        /// command run to select among the sorted lines
+        #[clap(short, long, value_parser, default_value = "fzf --no-sort")]
+        picker: String,
+    },
+    /// Print every entry of the cache ordered by value
+    List {
+        /// Value to sort by
+        #[clap(short = 'v', long = "mode", alias = "value", value_parser)]
+        value: Option<SavedValue>,
+
+        /// sort in descending order instead of ascending
+        #[clap(short, long, value_parser)]
+        desc: bool,
+
+        /// Output format
+        #[clap(short, long, value_parser, default_value = "plain")]
+        format: ListFormat,
+    },
+    /// Print everything saved about a line
+    Show {
+        /// Value to compute
+        #[clap(short = 'v', long = "mode", alias = "value", value_parser)]
+        value: Option<SavedValue>,
+
+        /// Line to show
+        #[clap(value_parser)]
+        key: OsString,
+    },
+    /// Forget lines
+    Remove {
+        /// Lines to forget
+        #[clap(value_parser, required_unless_present = "pattern")]
+        keys: Vec<OsString>,
+
+        /// also forget lines matching this glob, where `*` matches anything and `?` one character
+        #[clap(short, long, value_parser)]
+        pattern: Option<OsString>,
+    },
+    /// Forget every line of the cache
+    Reset,
+    /// Move everything saved about a line to another one, merged with what it already had
+    Rename {
+        /// Line to rename
+        #[clap(value_parser)]
+        from: OsString,
+
+        /// New line
+        #[clap(value_parser)]
+        to: OsString,
+    },
+    /// Put lines at the top or bottom of sorted lines whatever their value,
+    /// print the pinned keys if none is given
+    Pin {
+        /// Keys to pin
+        #[clap(value_parser)]
+        keys: Vec<OsString>,
+
+        /// where to pin them
+        #[clap(short, long, value_parser, default_value = "top")]
+        at: Pin,
+    },
+    /// Let lines be sorted by their value again
+    Unpin {
+        /// Keys to unpin
+        #[clap(value_parser, required = true)]
+        keys: Vec<OsString>,
+    },
+    /// Forget lines and never save them again, print the blocked keys if none is given
+    Block {
+        /// Keys to block
+        #[clap(value_parser)]
+        keys: Vec<OsString>,
+    },
+    /// Let lines be saved again
+    Unblock {
+        /// Keys to unblock
+        #[clap(value_parser, required = true)]
+        keys: Vec<OsString>,
+    },
+    /// Print every entry in a format other tools or another baus can import
+    Export {
+        /// Output format
+        #[clap(short, long, value_parser, default_value = "jsonl")]
+        format: ExchangeFormat,
+    },
+    /// Add the entries of an export, or of a file written by hand, to the cache
+    Import {
+        /// File to read, standard input when not given or -
+        #[clap(value_parser, value_name = "FILE")]
+        input: Option<PathBuf>,
+
+        /// Input format, json also reading whole cache files
+        #[clap(short, long, value_parser, default_value = "jsonl")]
+        format: ExchangeFormat,
+
+        /// read the history of that tool instead of an export
+        #[clap(long, value_parser, conflicts_with = "format")]
+        from: Option<History>,
+
+        /// How to combine imported entries with the ones already saved for the same lines
+        #[clap(short, long, value_parser, default_value = "sum")]
+        merge: Merge,
+    },
+    /// Merge other copies of a lines cache in, such as the one of another machine or conflicting
+    /// copies left by a sync, keeping the highest count of each device and adding the devices up
+    Merge {
+        /// Cache files to merge in, in any storage format
+        #[clap(value_parser, value_name = "FILE", required = true)]
+        others: Vec<PathBuf>,
+    },
+    /// Print the event log, oldest first: saves with their time, value and context,
+    /// along with removals and renames
+    Log,
+    /// Fold the event log into the cache file and empty it
+    Compact,
+    /// Recompute every entry from the event log with another value
+    Replay {
+        /// Value to recompute
+        #[clap(short = 'v', long = "mode", alias = "value", value_parser)]
+        value: Option<SavedValue>,
+    },
+    /// Convert the cache to another storage format
+    Migrate {
+        /// Format to convert to
+        #[clap(long, value_parser)]
+        to: Format,
+    },
+    /// Set the value of a line
+    Set {
+        /// Value to set
+        #[clap(short = 'v', long = "mode", alias = "value", value_parser)]
+        value: Option<SavedValue>,
+
+        /// Line to set
+        #[clap(value_parser)]
+        key: OsString,
+
+        /// count or timestamp to give to the line, frecency sets the count and marks the line as used now
+        #[clap(value_parser, allow_hyphen_values = true)]
+        score: i64,
+    },
+}
+
+impl Action {
+    /// Whether the action works on lines read from standard input
+    pub fn reads_stdin(&self) -> bool {
+        matches!(
+            self,
+            Action::Sort { .. } | Action::Save { .. } | Action::Pick { .. }
+        )
+    }
+
+    /// Where the saved lines are saved from
+    pub fn context(&self) -> Option<String> {
+        match self {
+            Action::Save { save, .. } | Action::Pick { save, .. } => save.context.clone(),
+            _ => None,
+        }
+    }
+}
+
+#[derive(clap::Args, Debug, Default)]
+pub struct KeyArgs {
+    /// save and sort lines by this field instead of the whole line, counted from 1
+    #[clap(
+        short = 'k',
+        long,
+        value_parser = parse_field,
+        global = true,
+        value_name = "N"
+    )]
+    pub field: Option<usize>,
+
+    /// delimiter of the fields, tab by default
+    #[clap(short = 't', long, value_parser, global = true, requires = "field")]
+    pub delimiter: Option<OsString>,
+
+    /// save and sort lines by what this regex matches instead of the whole line
+    #[clap(short, long, value_parser, global = true, conflicts_with = "field")]
+    pub regex: Option<String>,
+
+    /// capture group of the regex to use, its first one by default, 0 being the whole match
+    #[clap(
+        long,
+        value_parser,
+        global = true,
+        requires = "regex",
+        value_name = "N"
+    )]
+    pub group: Option<usize>,
+
+    /// rewrite keys so that equivalent lines share an entry, can be repeated or comma separated
+    #[clap(
+        long = "normalize",
+        value_parser,
+        global = true,
+        multiple_occurrences = true,
+        use_value_delimiter = true,
+        value_name = "NORMALIZER"
+    )]
+    pub normalizers: Vec<Normalizer>,
+}
+
+#[derive(clap::Args, Debug, Default)]
+pub struct AgingArgs {
+    /// when counts add up past this on save, scale them all down to 90% of it, not in lines caches
+    #[clap(long, value_parser, global = true, value_name = "N")]
+    pub max_total: Option<i64>,
+
+    /// drop entries whose count falls below this when scaled down, 1 by default
+    #[clap(long, value_parser, global = true, value_name = "N")]
+    pub min_count: Option<i64>,
+
+    /// halve the weight of counts for every such duration since a line was last used,
+    /// in seconds or with a s, m, h, d or w suffix
+    #[clap(long, value_parser = parse_duration, global = true, value_name = "DURATION")]
+    pub half_life: Option<i64>,
+}
+
+#[derive(clap::Args, Debug, Default)]
+pub struct LimitArgs {
+    /// keep at most this many entries in the cache when saving and cleaning up
+    #[clap(long, value_parser, global = true, value_name = "N")]
+    pub max_entries: Option<usize>,
+
+    /// which entries to drop first when there are too many, lowest-score by default
+    #[clap(long, value_parser, global = true)]
+    pub evict: Option<Eviction>,
+
+    /// keep at most this many saves in the event log, 1000 by default
+    #[clap(long, value_parser, global = true, value_name = "N")]
+    pub max_log_events: Option<usize>,
+}
+
+#[derive(clap::Args, Debug, Default)]
+pub struct SortArgs {
+    /// sort in descending order instead of ascending
+    #[clap(short, long, value_parser)]
+    pub desc: bool,
+
+    /// sort in ascending order, even when the config sets desc
+    #[clap(long, value_parser, conflicts_with = "desc")]
+    pub asc: bool,
+
+    /// keep only entries in sort in the cache
+    #[clap(short, long, value_parser)]
+    pub cleanup: bool,
+
+    /// keep every entry in the cache, even when the config sets cleanup
+    #[clap(long, value_parser, conflicts_with = "cleanup")]
+    pub no_cleanup: bool,
+
+    /// what to do with the lines of blocked keys, hide by default
+    #[clap(long, value_parser)]
+    pub blocked: Option<Blocked>,
+}
+
+#[derive(clap::Args, Debug, Default)]
+pub struct SaveArgs {
+    /// save every line read instead of only the first one
+    #[clap(short, long, value_parser)]
+    pub multi: bool,
+
+    /// save only the first N lines read, all of them being printed back
+    #[clap(short, long, value_parser, value_name = "N")]
+    pub first: Option<usize>,
+
+    /// where the lines are saved from, such as the current directory, kept in the event log
+    #[clap(long, value_parser, value_name = "TEXT")]
+    pub context: Option<String>,
+}
+
+impl SortArgs {
+    pub fn options(&self, value: &SavedValue, settings: &Settings) -> SortOptions {
+        SortOptions {
+            value: value.clone(),
+            desc: !self.asc && (self.desc || settings.desc.unwrap_or(false)),
+            cleanup: !self.no_cleanup && (self.cleanup || settings.cleanup.unwrap_or(false)),
+            blocked: self
+                .blocked
+                .clone()
+                .or_else(|| settings.blocked.clone())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+impl KeyArgs {
+    /// How lines are keyed, by the whole line unless asked otherwise
+    pub fn options(&self, settings: &Settings) -> Result<KeyOptions> {
+        let normalizers = match (&self.normalizers[..], &settings.normalize) {
+            ([], Some(normalizers)) => normalizers.clone(),
+            (normalizers, _) => normalizers.to_vec(),
+        };
+        Ok(KeyOptions {
+            extractor: self.extractor()?,
+            normalizers,
+        })
+    }
+
+    fn extractor(&self) -> Result<KeyExtractor> {
+        if let Some(pattern) = &self.regex {
+            return KeyExtractor::capture(pattern, self.group);
+        }
+        Ok(match self.field {
+            Some(index) => KeyExtractor::Field {
+                delimiter: self
+                    .delimiter
+                    .as_deref()
+                    .map(os_bytes)
+                    .unwrap_or_else(|| b"\t".to_vec()),
+                index,
+            },
+            None => KeyExtractor::Line,
+        })
+    }
+}
+
+impl AgingArgs {
+    pub fn options(&self, settings: &Settings) -> AgingOptions {
+        let default = AgingOptions::default();
+        AgingOptions {
+            max_total: self.max_total.or(settings.max_total),
+            min_count: self
+                .min_count
+                .or(settings.min_count)
+                .unwrap_or(default.min_count),
+            half_life: self.half_life.or(settings.half_life),
+        }
+    }
+}
+
+impl LimitArgs {
+    pub fn options(&self, settings: &Settings) -> LimitOptions {
+        LimitOptions {
+            max_entries: self.max_entries.or(settings.max_entries),
+            eviction: self
+                .evict
+                .clone()
+                .or_else(|| settings.evict.clone())
+                .unwrap_or_default(),
+            max_log_events: self
+                .max_log_events
+                .or(settings.max_log_events)
+                .unwrap_or(DEFAULT_MAX_LOG_EVENTS),
+        }
+    }
+}
+
+/// Value mode given on the command line, else in the config, else the one the cache
+/// was saved with, else counts
+pub fn mode(
+    value: &Option<SavedValue>,
+    settings: &Settings,
+    recorded: &Option<SavedValue>,
+) -> SavedValue {
+    value
+        .clone()
+        .or_else(|| settings.mode.clone())
+        .or_else(|| recorded.clone())
+        .unwrap_or_default()
+}
+
+/// Seconds in a duration such as `90`, `30m` or `2w`
+pub fn parse_duration(duration: &str) -> std::result::Result<i64, String> {
+    let (number, unit) = match duration.char_indices().last() {
+        Some((i, unit)) if unit.is_ascii_alphabetic() => (&duration[..i], unit),
+        _ => (duration, 's'),
+    };
+    let seconds = match unit {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        'w' => 7 * 24 * 60 * 60,
+        _ => return Err(format!("unknown unit `{}`, expected s, m, h, d or w", unit)),
+    };
+    match number.parse::<i64>() {
+        Ok(number) if number > 0 => Ok(number * seconds),
+        _ => Err(format!("`{}` is not a positive duration", duration)),
+    }
+}
+
+/// Index of a field, which are counted from 1
+pub fn parse_field(field: &str) -> std::result::Result<usize, String> {
+    match field.parse::<usize>() {
+        Ok(index) if index > 0 => Ok(index),
+        _ => Err(format!(
+            "`{}` is not a field, they are counted from 1",
+            field
+        )),
+    }
+}
+
+impl SaveArgs {
+    /// How many lines a save records, only the first one unless asked otherwise
+    pub fn max_lines(&self) -> usize {
+        match (self.first, self.multi) {
+            (Some(first), _) => first,
+            (None, true) => usize::MAX,
+            (None, false) => 1,
+        }
+    }
+}
+
+/// Bytes of a command line argument, lines being compared as bytes
+#[cfg(unix)]
+pub fn os_bytes(s: &OsStr) -> Vec<u8> {
+    use std::os::unix::ffi::OsStrExt;
+    s.as_bytes().to_vec()
+}
+
+/// Bytes of a command line argument, lines being compared as bytes
+#[cfg(not(unix))]
+pub fn os_bytes(s: &OsStr) -> Vec<u8> {
+    s.to_string_lossy().into_owned().into_bytes()
+}
+
+#[test]
+fn test_legacy_flags() {
+    let args = Args::parse_from([
+        "baus",
+        "--name",
+        "animals",
+        "--action",
+        "sort",
+        "--value",
+        "timestamp",
+        "-d",
+    ])
+    .with_legacy_flags();
+    assert_eq!(args.name.as_deref(), Some("animals"));
+    assert!(matches!(
+        args.action,
+        Some(Action::Sort {
+            value: Some(SavedValue::Timestamp),
+            sort: SortArgs {
+                desc: true,
+                cleanup: false,
+                ..
+            },
+            ..
+        })
+    ));
+    let args = Args::parse_from(["baus", "-n", "animals", "-a", "save"]).with_legacy_flags();
+    assert_eq!(args.name.as_deref(), Some("animals"));
+    assert!(matches!(
+        args.action,
+        Some(Action::Save { value: None, .. })
+    ));
+    assert!(Args::try_parse_from(["baus", "animals", "--name", "animals", "list"]).is_err());
+    assert!(Args::try_parse_from(["baus", "animals", "--desc", "list"]).is_err());
+}
+
+#[test]
+fn test_sort_flags_override_config() {
+    let settings = Settings {
+        desc: Some(true),
+        cleanup: Some(true),
+        ..Settings::default()
+    };
+    let options = |args: &[&str]| match Args::parse_from(args).action {
+        Some(Action::Sort { sort, .. }) => sort.options(&SavedValue::Count, &settings),
+        action => panic!("{:?}", action),
+    };
+    let configured = options(&["baus", "animals", "sort"]);
+    assert!(configured.desc && configured.cleanup);
+    let overridden = options(&["baus", "animals", "sort", "--asc", "--no-cleanup"]);
+    assert!(!overridden.desc && !overridden.cleanup);
+    assert!(Args::try_parse_from(["baus", "animals", "sort", "--asc", "--desc"]).is_err());
+}
+
+#[test]
+fn test_parse_duration() {
+    assert_eq!(parse_duration("90"), Ok(90));
+    assert_eq!(parse_duration("30m"), Ok(30 * 60));
+    assert_eq!(parse_duration("2w"), Ok(2 * 7 * 24 * 60 * 60));
+    assert!(parse_duration("0d").is_err());
+    assert!(parse_duration("3y").is_err());
+    assert!(parse_duration("d").is_err());
+}
+
+#[test]
+fn test_parse_field() {
+    assert_eq!(parse_field("2"), Ok(2));
+    assert!(parse_field("0").is_err());
+    assert!(parse_field("-1").is_err());
+    assert!(Args::try_parse_from(["baus", "animals", "sort", "--field", "0"]).is_err());
+}
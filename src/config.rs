@@ -0,0 +1,237 @@
+use crate::cli::parse_duration;
+use crate::error::{Error, Result};
+use crate::{Blocked, Eviction, Normalizer, SavedValue};
+use clap::ValueEnum;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use toml::{Table, Value};
+
+/// Defaults for the options of a cache, unset ones falling back to the global section
+/// then to the command line defaults, options given on the command line winning over all
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Settings {
+    pub mode: Option<SavedValue>,
+    pub desc: Option<bool>,
+    pub cleanup: Option<bool>,
+    pub blocked: Option<Blocked>,
+    pub half_life: Option<i64>,
+    pub max_total: Option<i64>,
+    pub min_count: Option<i64>,
+    pub max_entries: Option<usize>,
+    pub evict: Option<Eviction>,
+    pub max_log_events: Option<usize>,
+    pub normalize: Option<Vec<Normalizer>>,
+}
+
+impl Settings {
+    /// Takes the settings which are not set from the other ones
+    fn or(self, other: &Settings) -> Settings {
+        let other = other.clone();
+        Settings {
+            mode: self.mode.or(other.mode),
+            desc: self.desc.or(other.desc),
+            cleanup: self.cleanup.or(other.cleanup),
+            blocked: self.blocked.or(other.blocked),
+            half_life: self.half_life.or(other.half_life),
+            max_total: self.max_total.or(other.max_total),
+            min_count: self.min_count.or(other.min_count),
+            max_entries: self.max_entries.or(other.max_entries),
+            evict: self.evict.or(other.evict),
+            max_log_events: self.max_log_events.or(other.max_log_events),
+            normalize: self.normalize.or(other.normalize),
+        }
+    }
+}
+
+/// Settings of every cache, from keys before any section, and of each name, from `[name]` sections
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Config {
+    pub global: Settings,
+    pub names: HashMap<String, Settings>,
+}
+
+fn error(path: &Path, message: String) -> Error {
+    Error::Config {
+        path: path.to_path_buf(),
+        line: None,
+        message,
+    }
+}
+
+fn enum_value<T: ValueEnum>(path: &Path, key: &str, value: &Value) -> Result<T> {
+    match value {
+        Value::String(s) => T::from_str(s, false).map_err(|_| {
+            let expected: Vec<&str> = T::value_variants()
+                .iter()
+                .filter_map(|v| Some(v.to_possible_value()?.get_name()))
+                .collect();
+            error(
+                path,
+                format!("{} must be one of {}", key, expected.join(", ")),
+            )
+        }),
+        _ => Err(error(path, format!("{} must be a string", key))),
+    }
+}
+
+/// Reads the settings of a table, keys being named after their section in errors
+fn settings(path: &Path, section: Option<&str>, table: &Table) -> Result<Settings> {
+    let mut settings = Settings::default();
+    for (name, value) in table {
+        let key = match section {
+            Some(section) => format!("{}.{}", section, name),
+            None => name.clone(),
+        };
+        let key = key.as_str();
+        let boolean = |value: &Value| match value {
+            Value::Boolean(b) => Ok(*b),
+            _ => Err(error(path, format!("{} must be true or false", key))),
+        };
+        let integer = |value: &Value| match value {
+            Value::Integer(i) if *i >= 0 => Ok(*i),
+            _ => Err(error(path, format!("{} must be a positive integer", key))),
+        };
+        match name.as_str() {
+            "mode" => settings.mode = Some(enum_value(path, key, value)?),
+            "desc" => settings.desc = Some(boolean(value)?),
+            "cleanup" => settings.cleanup = Some(boolean(value)?),
+            "blocked" => settings.blocked = Some(enum_value(path, key, value)?),
+            "half_life" => {
+                settings.half_life = Some(match value {
+                    Value::String(duration) => parse_duration(duration)
+                        .map_err(|e| error(path, format!("{}: {}", key, e)))?,
+                    Value::Integer(seconds) if *seconds > 0 => *seconds,
+                    _ => return Err(error(path, format!("{} must be a positive duration", key))),
+                })
+            }
+            "max_total" => settings.max_total = Some(integer(value)?),
+            "min_count" => settings.min_count = Some(integer(value)?),
+            "max_entries" => settings.max_entries = Some(integer(value)? as usize),
+            "evict" => settings.evict = Some(enum_value(path, key, value)?),
+            "max_log_events" => settings.max_log_events = Some(integer(value)? as usize),
+            "normalize" => {
+                settings.normalize = Some(match value {
+                    Value::Array(values) => values
+                        .iter()
+                        .map(|value| enum_value(path, key, value))
+                        .collect::<Result<_>>()?,
+                    value => Vec::from([enum_value(path, key, value)?]),
+                })
+            }
+            _ => return Err(error(path, format!("unknown setting `{}`", key))),
+        }
+    }
+    Ok(settings)
+}
+
+impl Config {
+    /// Parses a TOML config: settings of every cache before any section,
+    /// then a `[name]` section for each cache which has its own
+    pub fn parse(path: &Path, contents: &str) -> Result<Config> {
+        let table: Table = contents
+            .parse()
+            .map_err(|e: toml::de::Error| Error::Config {
+                path: path.to_path_buf(),
+                line: e
+                    .span()
+                    .map(|span| contents[..span.start].matches('\n').count() + 1),
+                message: e.message().to_string(),
+            })?;
+        let (sections, global): (Table, Table) = table
+            .into_iter()
+            .partition(|(_, value)| matches!(value, Value::Table(_)));
+        let mut config = Config {
+            global: settings(path, None, &global)?,
+            names: HashMap::new(),
+        };
+        for (name, section) in sections {
+            if let Value::Table(section) = section {
+                let settings = settings(path, Some(&name), &section)?;
+                config.names.insert(name, settings);
+            }
+        }
+        Ok(config)
+    }
+
+    /// Where the config is read from, `BAUS_CONFIG` or `baus/config.toml` in the config directory
+    pub fn path() -> Option<PathBuf> {
+        match std::env::var_os("BAUS_CONFIG") {
+            Some(path) => Some(PathBuf::from(path)),
+            None => Some(dirs::config_dir()?.join("baus").join("config.toml")),
+        }
+    }
+
+    /// Reads the config, which is empty when there is no config file
+    pub fn load() -> Result<Config> {
+        let path = match Config::path() {
+            Some(path) if path.exists() => path,
+            _ => return Ok(Config::default()),
+        };
+        let contents = std::fs::read_to_string(&path)?;
+        Config::parse(&path, &contents)
+    }
+
+    /// Settings of the cache with that name, falling back to the global ones
+    pub fn settings(&self, name: &str) -> Settings {
+        match self.names.get(name) {
+            Some(settings) => settings.clone().or(&self.global),
+            None => self.global.clone(),
+        }
+    }
+}
+
+#[test]
+fn test_parse_config() {
+    let config = Config::parse(
+        Path::new("config.toml"),
+        r#"
+# defaults of every cache
+mode = "frecency"
+max_entries = 1_000
+
+[animals] # sorted by hand
+desc = true
+half_life = "2w"
+normalize = ["trim", 'ignore-case']
+
+["dirs#1"]
+mode = "timestamp"
+evict = "least-recently-used"
+"#,
+    )
+    .unwrap();
+    let animals = config.settings("animals");
+    assert_eq!(animals.mode, Some(SavedValue::Frecency));
+    assert_eq!(animals.max_entries, Some(1000));
+    assert_eq!(animals.desc, Some(true));
+    assert_eq!(animals.half_life, Some(2 * 7 * 24 * 60 * 60));
+    assert_eq!(
+        animals.normalize,
+        Some(Vec::from([Normalizer::Trim, Normalizer::IgnoreCase]))
+    );
+    let dirs = config.settings("dirs#1");
+    assert_eq!(dirs.mode, Some(SavedValue::Timestamp));
+    assert!(matches!(dirs.evict, Some(Eviction::LeastRecentlyUsed)));
+    assert_eq!(config.settings("other"), config.global);
+}
+
+#[test]
+fn test_config_errors() {
+    for (contents, line) in [
+        ("mode = \"count\"\nmode = \"timestamp\"", Some(2)),
+        ("[animals\ndesc = true", Some(1)),
+        ("desc = yes", Some(1)),
+        ("\n\nnormalize = [\"trim\" \"ignore-case\"]", Some(3)),
+        ("desc = true false", Some(1)),
+        ("mode = \"hours\"", None),
+        ("colour = \"red\"", None),
+        ("half_life = \"3y\"", None),
+        ("[animals]\nhalf_life = 0", None),
+        ("max_entries = -1", None),
+    ] {
+        match Config::parse(Path::new("config.toml"), contents) {
+            Err(Error::Config { line: l, .. }) => assert_eq!(l, line, "{}", contents),
+            other => panic!("{}: {:?}", contents, other),
+        }
+    }
+}
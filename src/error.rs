@@ -0,0 +1,171 @@
+use crate::SavedValue;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+/// Everything that can go wrong while using a cache
+#[derive(Debug)]
+pub enum Error {
+    /// reading or writing a file failed
+    Io(io::Error),
+    /// no directory to keep caches in was found for the user
+    NoCacheDir,
+    /// the cache file was written by a newer baus
+    UnsupportedVersion { path: PathBuf, version: i64 },
+    /// the line has no entry in the cache
+    NoEntry(Vec<u8>),
+    /// the picker command could not be run
+    Picker { command: String, source: io::Error },
+    /// the regular expression extracting keys does not parse
+    InvalidRegex { pattern: String, message: String },
+    /// the capture group extracting keys is not in the regular expression
+    NoGroup { pattern: String, group: usize },
+    /// the cache name could point outside of the cache directory or clash with another file
+    InvalidName { name: String, reason: String },
+    /// a SQLite cache could not be read or written
+    Sqlite(String),
+    /// the cache is or would become a SQLite database but baus was built without the sqlite feature
+    NoSqlite(PathBuf),
+    /// the event log cannot be replayed as its oldest saves were dropped
+    LogTrimmed { path: PathBuf, trimmed: u64 },
+    /// the entries hold changes the event log does not, which replaying it would lose
+    Unlogged(PathBuf),
+    /// entries to import do not parse
+    Import {
+        path: PathBuf,
+        line: usize,
+        message: String,
+    },
+    /// the config file does not parse, or one of its settings is invalid
+    Config {
+        path: PathBuf,
+        /// where the TOML syntax is wrong
+        line: Option<usize>,
+        message: String,
+    },
+    /// the cache to merge in does not exist
+    NoCacheFile(PathBuf),
+    /// the cache file does not parse, and is only set aside when opened as a store
+    Corrupt(PathBuf),
+    /// copies are only merged into lines caches, the only ones counting each device apart
+    MergeNotLines(PathBuf),
+    /// the action would scale or overwrite the counts of a lines cache, which only ever grow
+    /// so that the copies of each device merge
+    CountsPerDevice { path: PathBuf, action: String },
+    /// the cache was saved with another value mode
+    ModeMismatch {
+        path: PathBuf,
+        mode: SavedValue,
+        requested: SavedValue,
+    },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::NoCacheDir => write!(
+                f,
+                "did not find a directory to keep caches in, set BAUS_DIR"
+            ),
+            Error::UnsupportedVersion { path, version } => write!(
+                f,
+                "{} has version {} but this baus only reads up to version {}",
+                path.display(),
+                version,
+                crate::CACHE_VERSION
+            ),
+            Error::NoEntry(key) => write!(f, "no entry for `{}`", String::from_utf8_lossy(key)),
+            Error::Picker { command, source } => {
+                write!(f, "could not run picker `{}`: {}", command, source)
+            }
+            Error::InvalidRegex { pattern, message } => {
+                write!(f, "invalid regex `{}`: {}", pattern, message)
+            }
+            Error::NoGroup { pattern, group } => {
+                write!(f, "regex `{}` has no group {}", pattern, group)
+            }
+            Error::InvalidName { name, reason } => {
+                write!(f, "invalid cache name `{}`: {}", name, reason)
+            }
+            Error::Sqlite(message) => write!(f, "sqlite: {}", message),
+            Error::NoSqlite(path) => write!(
+                f,
+                "{} needs SQLite but baus was built without the sqlite feature",
+                path.display()
+            ),
+            Error::LogTrimmed { path, trimmed } => write!(
+                f,
+                "{} only holds the saves after number {}, raise max_log_events to keep every save",
+                path.display(),
+                trimmed
+            ),
+            Error::Unlogged(path) => write!(
+                f,
+                "{} holds imported, merged or set counts the event log does not, replaying would lose them",
+                path.display()
+            ),
+            Error::Import {
+                path,
+                line,
+                message,
+            } => write!(f, "{}:{}: {}", path.display(), line, message),
+            Error::Config {
+                path,
+                line: Some(line),
+                message,
+            } => write!(f, "{}:{}: {}", path.display(), line, message),
+            Error::Config {
+                path,
+                line: None,
+                message,
+            } => write!(f, "{}: {}", path.display(), message),
+            Error::NoCacheFile(path) => write!(f, "no cache file at {}", path.display()),
+            Error::Corrupt(path) => write!(
+                f,
+                "{} does not parse, it is set aside the next time it is saved to",
+                path.display()
+            ),
+            Error::MergeNotLines(path) => write!(
+                f,
+                "{} does not count the saves of each device apart, migrate it to lines to merge into it",
+                path.display()
+            ),
+            Error::CountsPerDevice { path, action } => write!(
+                f,
+                "{} counts the saves of each device apart for copies to merge, {} would break that, \
+                 migrate it to another format first",
+                path.display(),
+                action
+            ),
+            Error::ModeMismatch {
+                path,
+                mode,
+                requested,
+            } => write!(
+                f,
+                "{} is saved in {} mode, not {}, reset it to change its mode",
+                path.display(),
+                mode,
+                requested
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) | Error::Picker { source: e, .. } => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
@@ -0,0 +1,445 @@
+use crate::cache::{decode_key, encode_key, parse_cache};
+use crate::error::{Error, Result};
+use crate::{score, Entry, ExchangeFormat, LinesBackup, Merge, SavedValue};
+use miniserde::json;
+use miniserde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+/// Columns of CSV and TSV exports, only the key being required on import
+const HEADER: [&str; 5] = ["key", "count", "first_seen", "last_used", "score"];
+
+/// Entry on its line of a JSON lines export, with the key encoded by `encode_key`.
+/// Only the key is required on import
+#[derive(Serialize, Deserialize)]
+struct ExportedEntry {
+    key: String,
+    count: Option<i64>,
+    first_seen: Option<i64>,
+    last_used: Option<i64>,
+    score: Option<f64>,
+}
+
+/// Quotes CSV fields holding commas, quotes or line breaks, doubling their quotes
+fn csv_field(field: &[u8]) -> Vec<u8> {
+    if !field
+        .iter()
+        .any(|b| matches!(b, b',' | b'"' | b'\n' | b'\r'))
+    {
+        return field.to_vec();
+    }
+    let mut quoted = Vec::from([b'"']);
+    for &b in field {
+        if b == b'"' {
+            quoted.push(b'"');
+        }
+        quoted.push(b);
+    }
+    quoted.push(b'"');
+    quoted
+}
+
+/// Escapes backslashes, tabs and line breaks as `\\`, `\t`, `\n` and `\r`
+pub(crate) fn tsv_field(field: &[u8]) -> Vec<u8> {
+    let mut escaped = Vec::with_capacity(field.len());
+    for &b in field {
+        match b {
+            b'\\' => escaped.extend(b"\\\\"),
+            b'\t' => escaped.extend(b"\\t"),
+            b'\n' => escaped.extend(b"\\n"),
+            b'\r' => escaped.extend(b"\\r"),
+            b => escaped.push(b),
+        }
+    }
+    escaped
+}
+
+/// Undoes `tsv_field`, leaving other backslashes as they are
+pub(crate) fn tsv_unescape(field: &[u8]) -> Vec<u8> {
+    let mut unescaped = Vec::with_capacity(field.len());
+    let mut bytes = field.iter();
+    while let Some(&b) = bytes.next() {
+        if b != b'\\' {
+            unescaped.push(b);
+            continue;
+        }
+        match bytes.next() {
+            Some(b't') => unescaped.push(b'\t'),
+            Some(b'n') => unescaped.push(b'\n'),
+            Some(b'r') => unescaped.push(b'\r'),
+            Some(b'\\') => unescaped.push(b'\\'),
+            Some(&other) => unescaped.extend([b'\\', other]),
+            None => unescaped.push(b'\\'),
+        }
+    }
+    unescaped
+}
+
+/// Splits CSV into records of fields, along with the line each starts on,
+/// quoted fields spanning lines
+fn csv_records(path: &Path, input: &[u8]) -> Result<Vec<(usize, Vec<Vec<u8>>)>> {
+    let mut records = Vec::new();
+    let (mut record, mut field) = (Vec::new(), Vec::new());
+    let (mut line, mut start) = (1, 1);
+    let mut quoted = false;
+    let mut i = 0;
+    while i < input.len() {
+        let b = input[i];
+        i += 1;
+        match (quoted, b) {
+            (true, b'"') if input.get(i) == Some(&b'"') => {
+                field.push(b'"');
+                i += 1;
+            }
+            (true, b'"') => quoted = false,
+            (true, b) => {
+                line += usize::from(b == b'\n');
+                field.push(b);
+            }
+            (false, b'"') if field.is_empty() => quoted = true,
+            (false, b',') => record.push(std::mem::take(&mut field)),
+            (false, b'\r') if input.get(i) == Some(&b'\n') => (),
+            (false, b'\n') => {
+                record.push(std::mem::take(&mut field));
+                records.push((start, std::mem::take(&mut record)));
+                line += 1;
+                start = line;
+            }
+            (false, b) => field.push(b),
+        }
+    }
+    if quoted {
+        return Err(Error::Import {
+            path: path.to_path_buf(),
+            line: start,
+            message: "unclosed quote".to_string(),
+        });
+    }
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        records.push((start, record));
+    }
+    Ok(records)
+}
+
+/// Splits TSV into records of unescaped fields, along with their line
+fn tsv_records(input: &[u8]) -> Vec<(usize, Vec<Vec<u8>>)> {
+    input
+        .split(|b| *b == b'\n')
+        .enumerate()
+        .map(|(i, line)| {
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            let fields = line.split(|b| *b == b'\t').map(tsv_unescape).collect();
+            (i + 1, fields)
+        })
+        .collect()
+}
+
+fn number<T: std::str::FromStr>(field: Option<&Vec<u8>>) -> std::result::Result<Option<T>, ()> {
+    match field.map(|field| String::from_utf8_lossy(field).trim().to_string()) {
+        None => Ok(None),
+        Some(field) if field.is_empty() => Ok(None),
+        Some(field) => field.parse().map(Some).map_err(|_| ()),
+    }
+}
+
+/// An entry from its fields, missing ones counting one save at an unknown time
+fn imported_entry(
+    count: Option<i64>,
+    first_seen: Option<i64>,
+    last_used: Option<i64>,
+    score: Option<f64>,
+    saved_value: &SavedValue,
+) -> Entry {
+    let mut entry = Entry {
+        count: count.unwrap_or(1),
+        first_seen: first_seen.or(last_used).unwrap_or(0),
+        last_used: last_used.or(first_seen).unwrap_or(0),
+        ..Entry::new()
+    };
+    entry.score = score.unwrap_or_else(|| crate::score(saved_value, &entry, entry.last_used));
+    entry
+}
+
+/// Entries of CSV or TSV records, whose first one names the columns
+fn table_entries(
+    path: &Path,
+    records: Vec<(usize, Vec<Vec<u8>>)>,
+    saved_value: &SavedValue,
+) -> Result<Vec<(Vec<u8>, Entry)>> {
+    let mut records = records
+        .into_iter()
+        .filter(|(_, fields)| fields.iter().any(|field| !field.is_empty()));
+    let error = |line, message: &str| Error::Import {
+        path: path.to_path_buf(),
+        line,
+        message: message.to_string(),
+    };
+    let (line, header) = records
+        .next()
+        .ok_or_else(|| error(1, "expected a header"))?;
+    let column = |name: &str| header.iter().position(|field| field == name.as_bytes());
+    let key = column("key").ok_or_else(|| error(line, "expected a key column"))?;
+    let columns: Vec<Option<usize>> = HEADER[1..].iter().map(|name| column(name)).collect();
+    let mut entries = Vec::new();
+    for (line, fields) in records {
+        if fields.len() != header.len() {
+            return Err(error(
+                line,
+                &format!("expected {} fields, not {}", header.len(), fields.len()),
+            ));
+        }
+        let field = |i: usize| columns[i].map(|column| &fields[column]);
+        let invalid = |i: usize| error(line, &format!("invalid {}", HEADER[i + 1]));
+        let entry = imported_entry(
+            number(field(0)).map_err(|_| invalid(0))?,
+            number(field(1)).map_err(|_| invalid(1))?,
+            number(field(2)).map_err(|_| invalid(2))?,
+            number(field(3)).map_err(|_| invalid(3))?,
+            saved_value,
+        );
+        entries.push((fields[key].clone(), entry));
+    }
+    Ok(entries)
+}
+
+/// Every entry, ordered by line, in that format
+pub(crate) fn export(entries: &LinesBackup, format: ExchangeFormat) -> Vec<u8> {
+    let mut entries: Vec<(&Vec<u8>, &Entry)> = entries.iter().collect();
+    entries.sort_by(|a, b| a.0.cmp(b.0));
+    let table = |separator: u8, field: fn(&[u8]) -> Vec<u8>| -> Vec<u8> {
+        std::iter::once(HEADER.map(|name| name.as_bytes().to_vec()).to_vec())
+            .chain(entries.iter().map(|(key, entry)| {
+                Vec::from([
+                    field(key),
+                    entry.count.to_string().into_bytes(),
+                    entry.first_seen.to_string().into_bytes(),
+                    entry.last_used.to_string().into_bytes(),
+                    entry.score.to_string().into_bytes(),
+                ])
+            }))
+            .flat_map(|fields| [fields.join(&separator), b"\n".to_vec()].concat())
+            .collect()
+    };
+    match format {
+        ExchangeFormat::Jsonl => entries
+            .iter()
+            .flat_map(|(key, entry)| {
+                let exported = ExportedEntry {
+                    key: encode_key(key),
+                    count: Some(entry.count),
+                    first_seen: Some(entry.first_seen),
+                    last_used: Some(entry.last_used),
+                    score: Some(entry.score),
+                };
+                json::to_string(&exported)
+                    .into_bytes()
+                    .into_iter()
+                    .chain([b'\n'])
+            })
+            .collect(),
+        ExchangeFormat::Csv => table(b',', csv_field),
+        ExchangeFormat::Tsv => table(b'\t', tsv_field),
+        ExchangeFormat::Json => {
+            let map: BTreeMap<String, &Entry> = entries
+                .iter()
+                .map(|(key, entry)| (encode_key(key), *entry))
+                .collect();
+            (json::to_string(&map) + "\n").into_bytes()
+        }
+    }
+}
+
+/// Entries read in that format, in the order they come, scores missing from them being
+/// computed with the value. JSON may also be a whole cache file of any version
+pub(crate) fn import(
+    path: &Path,
+    input: &[u8],
+    format: ExchangeFormat,
+    saved_value: &SavedValue,
+) -> Result<Vec<(Vec<u8>, Entry)>> {
+    let text = || String::from_utf8_lossy(input);
+    match format {
+        ExchangeFormat::Jsonl => text()
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(i, line)| {
+                let exported: ExportedEntry = json::from_str(line).map_err(|_| Error::Import {
+                    path: path.to_path_buf(),
+                    line: i + 1,
+                    message: "expected an object with a key".to_string(),
+                })?;
+                let entry = imported_entry(
+                    exported.count,
+                    exported.first_seen,
+                    exported.last_used,
+                    exported.score,
+                    saved_value,
+                );
+                Ok((decode_key(&exported.key), entry))
+            })
+            .collect(),
+        ExchangeFormat::Csv => table_entries(path, csv_records(path, input)?, saved_value),
+        ExchangeFormat::Tsv => table_entries(path, tsv_records(input), saved_value),
+        ExchangeFormat::Json => {
+            let text = text();
+            let entries = match json::from_str::<HashMap<String, Entry>>(&text) {
+                Ok(entries) => entries
+                    .into_iter()
+                    .map(|(key, entry)| (decode_key(&key), entry)),
+                Err(_) => match parse_cache(path, &text)? {
+                    Some((cache, _)) => {
+                        let entries: Vec<(Vec<u8>, Entry)> = cache.entries.into_iter().collect();
+                        return Ok(entries);
+                    }
+                    None => {
+                        return Err(Error::Import {
+                            path: path.to_path_buf(),
+                            line: 1,
+                            message: "expected an object of entries or a cache file".to_string(),
+                        })
+                    }
+                },
+            };
+            Ok(entries.collect())
+        }
+    }
+}
+
+/// Combines an imported entry with the one the cache may hold for its line,
+/// the scores of summed entries being computed again with the value
+pub(crate) fn merge_entry(
+    entries: &mut LinesBackup,
+    key: Vec<u8>,
+    imported: Entry,
+    merge: Merge,
+    saved_value: &SavedValue,
+) {
+    let entry = match entries.get_mut(&key) {
+        Some(entry) => entry,
+        None => {
+            entries.insert(key, imported);
+            return;
+        }
+    };
+    match merge {
+        Merge::Overwrite => *entry = imported,
+        Merge::MaxTimestamp => {
+            if imported.last_used > entry.last_used {
+                *entry = imported;
+            }
+        }
+        Merge::Sum => {
+            entry.first_seen = match (entry.first_seen, imported.first_seen) {
+                (0, first_seen) | (first_seen, 0) => first_seen,
+                (a, b) => a.min(b),
+            };
+            entry.last_used = entry.last_used.max(imported.last_used);
+            entry.count += imported.count;
+            entry.score = score(saved_value, entry, entry.last_used);
+        }
+    }
+}
+
+#[test]
+fn test_export_import() {
+    let entries = LinesBackup::from([
+        (b"horse".to_vec(), crate::entry(2, 10)),
+        (b"a, \"quoted\"\n\tline\\".to_vec(), crate::entry(1, 20)),
+        (b"h\xff".to_vec(), crate::entry(3, 30)),
+    ]);
+    let path = Path::new("animals");
+    for format in [
+        ExchangeFormat::Jsonl,
+        ExchangeFormat::Csv,
+        ExchangeFormat::Tsv,
+        ExchangeFormat::Json,
+    ] {
+        let exported = export(&entries, format);
+        let imported = import(path, &exported, format, &SavedValue::Count).unwrap();
+        assert_eq!(
+            imported.into_iter().collect::<LinesBackup>(),
+            entries,
+            "{:?}",
+            format
+        );
+    }
+    assert_eq!(
+        export(&entries, ExchangeFormat::Csv)
+            .split(|b| *b == b'\n')
+            .next(),
+        Some(&b"key,count,first_seen,last_used,score"[..])
+    );
+    let partial = import(
+        path,
+        b"score,key\r\n7,horse\r\n,\"cat\"\r\n",
+        ExchangeFormat::Csv,
+        &SavedValue::Count,
+    )
+    .unwrap();
+    assert_eq!(partial[0].1.score, 7.0);
+    let cat = Entry {
+        score: 1.0,
+        ..crate::entry(1, 0)
+    };
+    assert_eq!(partial[1], (b"cat".to_vec(), cat));
+    let legacy = import(
+        path,
+        br#"{"horse": 2, "cat": 1700000000}"#,
+        ExchangeFormat::Json,
+        &SavedValue::Count,
+    )
+    .unwrap();
+    assert_eq!(legacy.len(), 2);
+    for (input, format, line) in [
+        (
+            &b"{\"key\":\"horse\"}\nnot json"[..],
+            ExchangeFormat::Jsonl,
+            2,
+        ),
+        (b"key\tcount\nhorse\t1\ncat\tmany", ExchangeFormat::Tsv, 3),
+        (b"count\n1", ExchangeFormat::Csv, 1),
+        (b"key,count\n\"horse,1", ExchangeFormat::Csv, 2),
+    ] {
+        match import(path, input, format, &SavedValue::Count) {
+            Err(Error::Import { line: l, .. }) => assert_eq!(l, line, "{:?}", format),
+            other => panic!("{:?}: {:?}", format, other),
+        }
+    }
+}
+
+#[test]
+fn test_merge_entry() {
+    let merged = |merge| {
+        let mut entries = LinesBackup::from([(b"horse".to_vec(), crate::entry(2, 20))]);
+        let imported = Entry {
+            first_seen: 5,
+            ..crate::entry(3, 10)
+        };
+        merge_entry(
+            &mut entries,
+            b"horse".to_vec(),
+            imported,
+            merge,
+            &SavedValue::Count,
+        );
+        merge_entry(
+            &mut entries,
+            b"cat".to_vec(),
+            crate::entry(1, 30),
+            merge,
+            &SavedValue::Count,
+        );
+        entries
+    };
+    let sum = merged(Merge::Sum);
+    let horse = sum.get(&b"horse"[..]).unwrap();
+    assert_eq!((horse.count, horse.first_seen, horse.last_used), (5, 5, 20));
+    assert_eq!(horse.score, 5.0);
+    assert_eq!(sum.get(&b"cat"[..]), Some(&crate::entry(1, 30)));
+    let latest = merged(Merge::MaxTimestamp);
+    assert_eq!(latest.get(&b"horse"[..]), Some(&crate::entry(2, 20)));
+    let overwritten = merged(Merge::Overwrite);
+    assert_eq!(overwritten.get(&b"horse"[..]).unwrap().count, 3);
+}
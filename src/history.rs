@@ -0,0 +1,252 @@
+use crate::error::{Error, Result};
+use crate::exchange::merge_entry;
+use crate::{score, Entry, History, LinesBackup, Merge, SavedValue};
+use clap::ValueEnum;
+use std::path::Path;
+
+/// A line of history counted that many times, last at that time when the history has it
+type Save = (Vec<u8>, i64, Option<i64>);
+
+/// Lines of the input, without the carriage return of CRLF endings
+fn input_lines(input: &[u8]) -> impl Iterator<Item = (usize, &[u8])> {
+    input
+        .split(|b| *b == b'\n')
+        .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+        .enumerate()
+        .map(|(i, line)| (i + 1, line))
+        .filter(|(_, line)| !line.trim_ascii().is_empty())
+}
+
+fn parse<T: std::str::FromStr>(field: &[u8]) -> Option<T> {
+    String::from_utf8_lossy(field).trim().parse().ok()
+}
+
+/// Ranks and weights of other tools are fractional, baus counts whole saves
+fn rank_count(rank: f64) -> i64 {
+    (rank.round() as i64).max(1)
+}
+
+/// Ranks of `zoxide query --list --score`, one `score path` line per directory
+fn zoxide(input: &[u8]) -> std::result::Result<Vec<Save>, usize> {
+    input_lines(input)
+        .map(|(i, line)| {
+            let line = line.trim_ascii_start();
+            let space = line.iter().position(|b| *b == b' ').ok_or(i)?;
+            let rank = parse(&line[..space]).ok_or(i)?;
+            Ok((line[space + 1..].to_vec(), rank_count(rank), None))
+        })
+        .collect()
+}
+
+/// Data files of fasd and z, one `path|rank|time` line per path
+fn fasd(input: &[u8]) -> std::result::Result<Vec<Save>, usize> {
+    input_lines(input)
+        .map(|(i, line)| {
+            let mut fields = line.rsplitn(3, |b| *b == b'|');
+            let time = fields.next().and_then(parse).ok_or(i)?;
+            let rank = fields.next().and_then(parse).ok_or(i)?;
+            let path = fields.next().ok_or(i)?;
+            Ok((path.to_vec(), rank_count(rank), Some(time)))
+        })
+        .collect()
+}
+
+/// Text database of autojump, one `weight<TAB>path` line per directory
+fn autojump(input: &[u8]) -> std::result::Result<Vec<Save>, usize> {
+    input_lines(input)
+        .map(|(i, line)| {
+            let tab = line.iter().position(|b| *b == b'\t').ok_or(i)?;
+            let weight = parse(&line[..tab]).ok_or(i)?;
+            Ok((line[tab + 1..].to_vec(), rank_count(weight), None))
+        })
+        .collect()
+}
+
+/// One command per line, preceded by a `#time` line when HISTTIMEFORMAT is set
+fn bash(input: &[u8]) -> Vec<Save> {
+    let mut saves = Vec::new();
+    let mut time = None;
+    for (_, line) in input_lines(input) {
+        match line.strip_prefix(b"#").and_then(parse::<i64>) {
+            Some(t) => time = Some(t),
+            None => saves.push((line.to_vec(), 1, time.take())),
+        }
+    }
+    saves
+}
+
+/// Marks bytes zsh escapes in its history, the next one being xored with 32
+const ZSH_META: u8 = 0x83;
+
+/// Commands of zsh, plain or `: time:duration;command` with EXTENDED_HISTORY, lines ending
+/// with a backslash going on on the next one
+fn zsh(input: &[u8]) -> Vec<Save> {
+    let mut unmetafied = Vec::with_capacity(input.len());
+    let mut bytes = input.iter();
+    while let Some(&b) = bytes.next() {
+        match b {
+            ZSH_META => unmetafied.extend(bytes.next().map(|b| b ^ 32)),
+            b => unmetafied.push(b),
+        }
+    }
+    let mut saves = Vec::new();
+    let mut command: Option<Vec<u8>> = None;
+    for (_, line) in input_lines(&unmetafied) {
+        let mut line = match command.take() {
+            Some(command) => [&command[..], b"\n", line].concat(),
+            None => line.to_vec(),
+        };
+        if line.ends_with(b"\\") {
+            line.pop();
+            command = Some(line);
+            continue;
+        }
+        let extended = line.strip_prefix(b": ").and_then(|rest| {
+            let semicolon = rest.iter().position(|b| *b == b';')?;
+            let time = rest[..semicolon].split(|b| *b == b':').next()?;
+            Some((parse(time)?, rest[semicolon + 1..].to_vec()))
+        });
+        saves.push(match extended {
+            Some((time, command)) => (command, 1, Some(time)),
+            None => (line, 1, None),
+        });
+    }
+    saves.extend(command.map(|command| (command, 1, None)));
+    saves
+}
+
+/// Undoes the escaping of backslashes and newlines in fish commands
+fn fish_unescape(command: &[u8]) -> Vec<u8> {
+    let mut unescaped = Vec::with_capacity(command.len());
+    let mut bytes = command.iter();
+    while let Some(&b) = bytes.next() {
+        match (b, bytes.as_slice().first()) {
+            (b'\\', Some(b'n')) => {
+                unescaped.push(b'\n');
+                bytes.next();
+            }
+            (b'\\', Some(b'\\')) => {
+                unescaped.push(b'\\');
+                bytes.next();
+            }
+            (b, _) => unescaped.push(b),
+        }
+    }
+    unescaped
+}
+
+/// Commands of fish, `- cmd: command` items followed by a `when: time` line
+fn fish(input: &[u8]) -> Vec<Save> {
+    let mut saves: Vec<Save> = Vec::new();
+    for (_, line) in input_lines(input) {
+        if let Some(command) = line.strip_prefix(b"- cmd: ") {
+            saves.push((fish_unescape(command), 1, None));
+        } else if let Some(time) = line.trim_ascii_start().strip_prefix(b"when: ") {
+            if let Some(save) = saves.last_mut() {
+                save.2 = parse(time);
+            }
+        }
+    }
+    saves
+}
+
+/// Entries seeded from the history of another tool, one per line however many times it appears,
+/// lines without a time counting as saved now
+pub(crate) fn import_history(
+    path: &Path,
+    input: &[u8],
+    history: History,
+    saved_value: &SavedValue,
+    now: i64,
+) -> Result<Vec<(Vec<u8>, Entry)>> {
+    let saves = match history {
+        History::Zoxide => zoxide(input),
+        History::Fasd => fasd(input),
+        History::Autojump => autojump(input),
+        History::Bash => Ok(bash(input)),
+        History::Zsh => Ok(zsh(input)),
+        History::Fish => Ok(fish(input)),
+    }
+    .map_err(|line| Error::Import {
+        path: path.to_path_buf(),
+        line,
+        message: match history.to_possible_value() {
+            Some(value) => format!("not a line of a {} history", value.get_name()),
+            None => "not a line of history".to_string(),
+        },
+    })?;
+    let mut entries = LinesBackup::new();
+    for (key, count, time) in saves {
+        let time = time.unwrap_or(now);
+        let mut entry = Entry {
+            first_seen: time,
+            last_used: time,
+            count,
+            ..Entry::new()
+        };
+        entry.score = score(saved_value, &entry, time);
+        merge_entry(&mut entries, key, entry, Merge::Sum, saved_value);
+    }
+    Ok(entries.into_iter().collect())
+}
+
+#[test]
+fn test_import_history() {
+    let imported = |input: &[u8], history| -> LinesBackup {
+        import_history(Path::new("history"), input, history, &SavedValue::Count, 50)
+            .unwrap()
+            .into_iter()
+            .collect()
+    };
+    let counts = |entries: &LinesBackup| {
+        let mut counts: Vec<(String, i64, i64)> = entries
+            .iter()
+            .map(|(key, entry)| {
+                let key = String::from_utf8_lossy(key).into_owned();
+                (key, entry.count, entry.last_used)
+            })
+            .collect();
+        counts.sort();
+        counts
+    };
+    let zoxide = imported(b"  12.4 /home/me\n   0.2 /tmp/a b\n", History::Zoxide);
+    assert_eq!(
+        counts(&zoxide),
+        [("/home/me".into(), 12, 50), ("/tmp/a b".into(), 1, 50)]
+    );
+    let fasd = imported(b"/srv/a|b|3.6|40\n", History::Fasd);
+    assert_eq!(counts(&fasd), [("/srv/a|b".into(), 4, 40)]);
+    let autojump = imported(b"22.0\t/home/me\r\n", History::Autojump);
+    assert_eq!(counts(&autojump), [("/home/me".into(), 22, 50)]);
+    let bash = imported(b"#30\nls\ncd /\n#40\nls\n", History::Bash);
+    assert_eq!(
+        counts(&bash),
+        [("cd /".into(), 1, 50), ("ls".into(), 2, 40)]
+    );
+    let zsh = imported(
+        b": 30:0;ls\n: 40:2;echo a\\\nb\nls\n: 45:0;echo \x83\xa3\n",
+        History::Zsh,
+    );
+    assert_eq!(zsh.get(&b"echo \x83"[..]).unwrap().last_used, 45);
+    assert_eq!(zsh.get(&b"echo a\nb"[..]).unwrap().last_used, 40);
+    let ls = zsh.get(&b"ls"[..]).unwrap();
+    assert_eq!((ls.count, ls.first_seen, ls.last_used), (2, 30, 50));
+    let fish = imported(
+        b"- cmd: echo a\\nb\n  when: 30\n- cmd: ls\n  when: 40\n  paths:\n    - /tmp\n",
+        History::Fish,
+    );
+    assert_eq!(
+        counts(&fish),
+        [("echo a\nb".into(), 1, 30), ("ls".into(), 1, 40)]
+    );
+    assert!(matches!(
+        import_history(
+            Path::new("history"),
+            b"/srv|1|2\n/srv|x|2\n",
+            History::Fasd,
+            &SavedValue::Count,
+            50,
+        ),
+        Err(Error::Import { line: 2, .. })
+    ));
+}
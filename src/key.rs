@@ -0,0 +1,302 @@
+use crate::error::{Error, Result};
+use clap::ValueEnum;
+use regex::bytes::Regex;
+use std::borrow::Cow;
+use std::path::{Component, Path, PathBuf};
+
+/// Which part of a line its entry is saved under, lines being output whole whatever the key
+#[derive(Clone, Debug, Default)]
+pub enum KeyExtractor {
+    /// the whole line
+    #[default]
+    Line,
+    /// the field at that index, counted from 1, of the line split on the delimiter
+    Field { delimiter: Vec<u8>, index: usize },
+    /// the text captured by that group of the regex, 0 being the whole match
+    Capture { regex: Regex, group: usize },
+}
+
+/// Splits on every occurrence of a delimiter of any length
+fn split_fields<'a>(line: &'a [u8], delimiter: &[u8]) -> Vec<&'a [u8]> {
+    let mut fields = Vec::new();
+    let mut start = 0;
+    let mut i = 0;
+    while !delimiter.is_empty() && i + delimiter.len() <= line.len() {
+        if line[i..].starts_with(delimiter) {
+            fields.push(&line[start..i]);
+            i += delimiter.len();
+            start = i;
+        } else {
+            i += 1;
+        }
+    }
+    fields.push(&line[start..]);
+    fields
+}
+
+impl KeyExtractor {
+    /// Keys lines by a group of the regex, by default its first one or the whole match if it has none
+    pub fn capture(pattern: &str, group: Option<usize>) -> Result<KeyExtractor> {
+        let regex = Regex::new(pattern).map_err(|e| Error::InvalidRegex {
+            pattern: pattern.to_string(),
+            message: e.to_string(),
+        })?;
+        let groups = regex.captures_len() - 1;
+        let group = group.unwrap_or(groups.min(1));
+        if group > groups {
+            return Err(Error::NoGroup {
+                pattern: pattern.to_string(),
+                group,
+            });
+        }
+        Ok(KeyExtractor::Capture { regex, group })
+    }
+
+    /// Key of the line, which is the whole line when the field or the match is missing
+    pub fn extract<'a>(&self, line: &'a [u8]) -> &'a [u8] {
+        let key = match self {
+            KeyExtractor::Line => None,
+            KeyExtractor::Field { delimiter, index } => index
+                .checked_sub(1)
+                .and_then(|i| split_fields(line, delimiter).get(i).copied()),
+            KeyExtractor::Capture { regex, group } => regex
+                .captures(line)
+                .and_then(|captures| captures.get(*group))
+                .map(|found| found.as_bytes()),
+        };
+        key.unwrap_or(line)
+    }
+}
+
+/// Rewrites keys so that lines meaning the same thing share an entry
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Normalizer {
+    /// remove color and other terminal escape sequences
+    StripAnsi,
+    /// remove leading and trailing whitespace
+    Trim,
+    /// replace runs of whitespace with one space
+    CollapseWhitespace,
+    /// compare without case
+    IgnoreCase,
+    /// make paths absolute, expanding `~` and resolving `.`, `..` and symbolic links
+    CanonicalizePath,
+}
+
+/// Removes CSI sequences such as colors, OSC sequences such as hyperlinks and other two byte escapes
+fn strip_ansi(key: &[u8]) -> Vec<u8> {
+    let mut stripped = Vec::with_capacity(key.len());
+    let mut i = 0;
+    while i < key.len() {
+        if key[i] != 0x1b {
+            stripped.push(key[i]);
+            i += 1;
+            continue;
+        }
+        i += 1;
+        match key.get(i) {
+            Some(b'[') => {
+                i += 1;
+                while i < key.len() && !(0x40..=0x7e).contains(&key[i]) {
+                    i += 1;
+                }
+                i += 1;
+            }
+            Some(b']') => {
+                i += 1;
+                while i < key.len() && key[i] != 0x07 && !key[i..].starts_with(b"\x1b\\") {
+                    i += 1;
+                }
+                i += if key.get(i) == Some(&0x07) { 1 } else { 2 };
+            }
+            Some(_) => i += 1,
+            None => (),
+        }
+    }
+    stripped
+}
+
+fn collapse_whitespace(key: &[u8]) -> Vec<u8> {
+    let mut collapsed = Vec::with_capacity(key.len());
+    for &b in key {
+        if !b.is_ascii_whitespace() {
+            collapsed.push(b);
+        } else if collapsed.last() != Some(&b' ') {
+            collapsed.push(b' ');
+        }
+    }
+    collapsed
+}
+
+fn ignore_case(key: &[u8]) -> Vec<u8> {
+    match std::str::from_utf8(key) {
+        Ok(key) => key.to_lowercase().into_bytes(),
+        Err(_) => key.to_ascii_lowercase(),
+    }
+}
+
+#[cfg(unix)]
+fn bytes_path(bytes: &[u8]) -> PathBuf {
+    use std::os::unix::ffi::OsStrExt;
+    PathBuf::from(std::ffi::OsStr::from_bytes(bytes))
+}
+
+#[cfg(not(unix))]
+fn bytes_path(bytes: &[u8]) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(bytes).into_owned())
+}
+
+#[cfg(unix)]
+fn path_bytes(path: &Path) -> Vec<u8> {
+    use std::os::unix::ffi::OsStrExt;
+    path.as_os_str().as_bytes().to_vec()
+}
+
+#[cfg(not(unix))]
+fn path_bytes(path: &Path) -> Vec<u8> {
+    path.to_string_lossy().into_owned().into_bytes()
+}
+
+/// Resolves the path if it exists, otherwise only makes it absolute and drops `.` and `..`,
+/// keys are left alone when they cannot be made absolute
+fn canonicalize_path(key: &[u8]) -> Vec<u8> {
+    let path = bytes_path(key);
+    let path = match path.strip_prefix("~") {
+        Ok(rest) => match dirs::home_dir() {
+            Some(home) => home.join(rest),
+            None => return key.to_vec(),
+        },
+        Err(_) => path,
+    };
+    let path = match std::env::current_dir() {
+        Ok(dir) => dir.join(path),
+        Err(_) => path,
+    };
+    if let Ok(path) = std::fs::canonicalize(&path) {
+        return path_bytes(&path);
+    }
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    path_bytes(&normalized)
+}
+
+impl Normalizer {
+    pub fn apply(&self, key: &[u8]) -> Vec<u8> {
+        match self {
+            Normalizer::StripAnsi => strip_ansi(key),
+            Normalizer::Trim => key.trim_ascii().to_vec(),
+            Normalizer::CollapseWhitespace => collapse_whitespace(key),
+            Normalizer::IgnoreCase => ignore_case(key),
+            Normalizer::CanonicalizePath => canonicalize_path(key),
+        }
+    }
+}
+
+/// How the key of a line is found, used alike when sorting and saving
+#[derive(Clone, Debug, Default)]
+pub struct KeyOptions {
+    pub extractor: KeyExtractor,
+    /// applied after extraction in the order of `Normalizer`, whatever order they are given in
+    pub normalizers: Vec<Normalizer>,
+}
+
+impl KeyOptions {
+    pub fn key<'a>(&self, line: &'a [u8]) -> Cow<'a, [u8]> {
+        self.normalize(self.extractor.extract(line))
+    }
+
+    /// Normalizes a key given as is, such as one from the command line, without extracting it
+    pub fn normalize<'a>(&self, key: &'a [u8]) -> Cow<'a, [u8]> {
+        Normalizer::value_variants()
+            .iter()
+            .filter(|normalizer| self.normalizers.contains(normalizer))
+            .fold(Cow::Borrowed(key), |key, normalizer| {
+                Cow::Owned(normalizer.apply(&key))
+            })
+    }
+}
+
+#[test]
+fn test_key_extraction() {
+    let field = KeyExtractor::Field {
+        delimiter: b"\t".to_vec(),
+        index: 1,
+    };
+    assert_eq!(field.extract(b"42\thorse"), b"42");
+    assert_eq!(field.extract(b"horse"), b"horse");
+    let field = KeyExtractor::Field {
+        delimiter: b"::".to_vec(),
+        index: 3,
+    };
+    assert_eq!(field.extract(b"a::b::c::d"), b"c");
+    assert_eq!(field.extract(b"a::b"), b"a::b");
+    let capture = KeyExtractor::capture("^([^:]*):\\d+:", None).unwrap();
+    assert_eq!(capture.extract(b"src/lib.rs:12:fn"), b"src/lib.rs");
+    assert_eq!(capture.extract(b"no match"), b"no match");
+    let whole = KeyExtractor::capture("\\d+", None).unwrap();
+    assert_eq!(whole.extract(b"horse 42"), b"42");
+    let lazy = KeyExtractor::capture("<(.+?)>", None).unwrap();
+    assert_eq!(lazy.extract(b"\xff<a><b>"), b"a");
+    assert!(matches!(
+        KeyExtractor::capture("(a)", Some(2)),
+        Err(Error::NoGroup { group: 2, .. })
+    ));
+    assert!(matches!(
+        KeyExtractor::capture("(horse", None),
+        Err(Error::InvalidRegex { .. })
+    ));
+}
+
+#[test]
+fn test_normalizers() {
+    assert_eq!(
+        Normalizer::StripAnsi
+            .apply(b"\x1b[01;34msrc\x1b[0m \x1b]8;;file:///src\x1b\\x\x1b]8;;\x07"),
+        b"src x"
+    );
+    assert_eq!(Normalizer::Trim.apply(b" \thorse \r"), b"horse");
+    assert_eq!(
+        Normalizer::CollapseWhitespace.apply(b"my  \t horse "),
+        b"my horse "
+    );
+    assert_eq!(
+        Normalizer::IgnoreCase.apply("Hörse".as_bytes()),
+        "hörse".as_bytes()
+    );
+    assert_eq!(Normalizer::IgnoreCase.apply(b"H\xff"), b"h\xff");
+    let dir = crate::cache::test_dir("normalize");
+    std::fs::create_dir_all(dir.join("horse")).unwrap();
+    let horse = path_bytes(&std::fs::canonicalize(dir.join("horse")).unwrap());
+    let canonical = |path: &Path| Normalizer::CanonicalizePath.apply(&path_bytes(path));
+    let resolved = canonical(&dir.join("horse/../horse/"));
+    let missing = canonical(&dir.join("horse/./../hamster/"));
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(resolved, horse);
+    assert_eq!(missing, path_bytes(&dir.join("hamster")));
+    let options = KeyOptions {
+        extractor: KeyExtractor::Line,
+        normalizers: Vec::from([
+            Normalizer::IgnoreCase,
+            Normalizer::StripAnsi,
+            Normalizer::Trim,
+        ]),
+    };
+    assert_eq!(options.key(b" \x1b[1mFoo\x1b[0m "), &b"foo"[..]);
+    assert_eq!(KeyOptions::default().key(b" Foo"), &b" Foo"[..]);
+    let options = KeyOptions {
+        extractor: KeyExtractor::Field {
+            delimiter: b"\t".to_vec(),
+            index: 2,
+        },
+        normalizers: Vec::from([Normalizer::IgnoreCase]),
+    };
+    assert_eq!(options.normalize(b"42\tHorse"), &b"42\thorse"[..]);
+}
@@ -1,201 +1,507 @@
-use clap::Parser;
+// miniserde derives implement their traits from inside a const block
+#![allow(non_local_definitions)]
 use clap::ValueEnum;
-use miniserde::json;
-use std::collections::HashMap;
-use std::error::Error;
-use std::fs::create_dir_all;
-use std::fs::File;
+use miniserde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::io::prelude::*;
-use std::path::Path;
-use std::path::PathBuf;
 use std::time::{SystemTime, UNIX_EPOCH};
 
-#[derive(ValueEnum, Clone, Debug)]
-pub enum Action {
-    Sort,
-    Save,
-}
+mod cache;
+pub mod cli;
+mod config;
+mod error;
+mod exchange;
+mod history;
+mod key;
+mod log;
+mod picker;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+mod store;
+mod sync;
 
-#[derive(ValueEnum, Clone, Debug)]
+pub use cache::{
+    cache_file_path_in, get_cache, get_cache_dir, get_cache_file_path, get_format,
+    get_lines_backup, list_caches, lock_cache, CacheInfo, SetAside,
+};
+pub use config::{Config, Settings};
+pub use error::{Error, Result};
+pub use key::{KeyExtractor, KeyOptions, Normalizer};
+pub use log::{Change, Event, DEFAULT_MAX_LOG_EVENTS};
+pub use store::Store;
+pub use sync::get_device;
+
+#[derive(ValueEnum, Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
 pub enum SavedValue {
+    #[serde(rename = "timestamp")]
     Timestamp,
+    #[default]
+    #[serde(rename = "count")]
     Count,
+    #[serde(rename = "frecency")]
+    Frecency,
 }
 
-#[derive(Parser, Debug)]
-#[clap(author, version, about, long_about = None)]
-pub struct Args {
-    /// Name of the sort to backup
-    #[clap(short, long, value_parser)]
-    pub name: String,
+impl std::fmt::Display for SavedValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.to_possible_value() {
+            Some(value) => write!(f, "{}", value.get_name()),
+            None => write!(f, "{:?}", self),
+        }
+    }
+}
 
-    /// Mode to run
-    #[clap(short, long, value_parser)]
-    pub action: Action,
+/// Version of the cache file layout written by this baus
+pub const CACHE_VERSION: i64 = 3;
 
-    /// Value to save
-    #[clap(short, long, value_parser, default_value = "count")]
-    pub value: SavedValue,
+/// What is remembered for each line of a cache
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Entry {
+    /// unix time of the first save
+    pub first_seen: i64,
+    /// unix time of the last save
+    pub last_used: i64,
+    /// number of times the line was saved
+    pub count: i64,
+    /// score of the line when it was last saved
+    pub score: f64,
+}
+
+impl Entry {
+    pub fn new() -> Entry {
+        <Entry as Default>::default()
+    }
+}
+
+/// Entries of a cache, by line, lines being kept as raw bytes
+pub type LinesBackup = HashMap<Vec<u8>, Entry>;
+
+/// Where a pinned line goes in the output, whatever its value
+#[derive(ValueEnum, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Pin {
+    #[serde(rename = "top")]
+    Top,
+    #[serde(rename = "bottom")]
+    Bottom,
+}
+
+/// How a cache file is stored
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum Format {
+    /// one JSON object, rewritten whole on every change
+    #[default]
+    Json,
+    /// a SQLite database, only changed rows being written, needs the sqlite feature
+    Sqlite,
+    /// sorted tab separated lines counting the saves of each device apart, for caches synced
+    /// between machines
+    Lines,
+}
+
+/// What one device counted, as kept by caches in the lines format
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DeviceCounts {
+    /// entries as saved on that device alone, their scores left at zero
+    pub entries: LinesBackup,
+    /// number of the last save of the event log of that device folded into its entries
+    pub last_event: u64,
+    /// number of the last save dropped from the event log of that device
+    pub trimmed: u64,
+    /// lines that device removed, with the last use of what it removed: rows of any device
+    /// not used since then do not count anymore
+    pub removed: HashMap<Vec<u8>, i64>,
+}
+
+/// Everything a cache file holds
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Cache {
+    /// value the entries were saved with, set by the first save
+    pub mode: Option<SavedValue>,
+    pub entries: LinesBackup,
+    /// keys placed at the top or bottom of sorted lines
+    pub pinned: HashMap<Vec<u8>, Pin>,
+    /// keys which are never saved nor ranked
+    pub blocked: HashSet<Vec<u8>>,
+    /// number of the last save of the event log folded into the entries
+    pub last_event: u64,
+    /// number of the last save dropped from the event log, which cannot be replayed past it
+    pub trimmed: u64,
+    /// whether the entries hold changes the event log does not, such as imports,
+    /// which replaying it would lose
+    pub unlogged: bool,
+    /// what each device counted, for caches in the lines format, `entries` adding them up
+    pub devices: BTreeMap<String, DeviceCounts>,
+}
 
+#[derive(ValueEnum, Clone, Debug)]
+pub enum ListFormat {
+    /// value and line separated by a tab
+    Plain,
+    /// one JSON array of entries
+    Json,
+    /// every field of the entries, tab separated, after a header
+    Tsv,
+}
+
+/// Formats entries are exported to and imported from
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum ExchangeFormat {
+    /// one JSON object per line
+    #[default]
+    Jsonl,
+    /// comma separated values after a header, fields quoted when needed
+    Csv,
+    /// tab separated values after a header, with backslashes, tabs and line breaks escaped
+    Tsv,
+    /// one JSON object mapping lines to their entry, as in cache files
+    Json,
+}
+
+/// Tools whose history can seed a cache
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum History {
+    /// the output of `zoxide query --list --score`
+    Zoxide,
+    /// the data file of fasd or z, `~/.fasd` or `~/.z`
+    Fasd,
+    /// the text database of autojump, `autojump.txt`
+    Autojump,
+    /// `~/.bash_history`, with the times of commands when HISTTIMEFORMAT is set
+    Bash,
+    /// `~/.zsh_history`, with the times of commands when EXTENDED_HISTORY is set
+    Zsh,
+    /// `~/.local/share/fish/fish_history`
+    Fish,
+}
+
+/// How imported entries are combined with the ones the cache holds for the same lines
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum Merge {
+    /// add the counts up, keeping the first and last uses of both
+    #[default]
+    Sum,
+    /// keep whichever entry was used last
+    MaxTimestamp,
+    /// replace the entries with the imported ones
+    Overwrite,
+}
+
+/// What sorting does with the lines of blocked keys
+#[derive(ValueEnum, Clone, Debug, Default, PartialEq)]
+pub enum Blocked {
+    /// leave them out of the output
+    #[default]
+    Hide,
+    /// output them as if they were never saved
+    Unranked,
+}
+
+/// How lines are sorted
+#[derive(Clone, Debug, Default)]
+pub struct SortOptions {
+    /// value to sort by
+    pub value: SavedValue,
     /// sort in descending order instead of ascending
-    #[clap(short, long, value_parser)]
     pub desc: bool,
-
-    /// keep only entries in sort in the cache
-    #[clap(short, long, value_parser)]
+    /// keep only the sorted lines in the cache
     pub cleanup: bool,
+    /// what to do with the lines of blocked keys
+    pub blocked: Blocked,
+}
+
+/// Keeps counts from growing forever and lets lines which are not used anymore fade, like zoxide
+#[derive(Clone, Debug)]
+pub struct AgingOptions {
+    /// when the counts of a cache add up past this on save, they are all scaled down to 90% of it
+    pub max_total: Option<i64>,
+    /// entries whose count falls below this when scaled down are dropped
+    pub min_count: i64,
+    /// seconds after which counts weigh half as much when sorting
+    pub half_life: Option<i64>,
 }
 
-fn backup_lines(
-    cache_file_path: &Path,
-    lines_backup: &HashMap<String, i64>,
-) -> Result<(), Box<dyn Error>> {
-    let mut file = File::create(cache_file_path)?;
-    file.write_all(json::to_string(&lines_backup).as_bytes())?;
-    Ok(())
+impl Default for AgingOptions {
+    fn default() -> AgingOptions {
+        AgingOptions {
+            max_total: None,
+            min_count: 1,
+            half_life: None,
+        }
+    }
 }
 
-fn load_lines(cache_file_path: &Path) -> Result<HashMap<String, i64>, Box<dyn Error>> {
-    let mut file = File::open(cache_file_path)?;
-    let mut contents = String::new();
-    file.read_to_string(&mut contents)?;
-    let content_str = &contents.as_str();
-    json::from_str(content_str).map_err(|x| x.into())
+/// Which entries go first when a cache holds too many
+#[derive(ValueEnum, Clone, Debug, Default, PartialEq)]
+pub enum Eviction {
+    /// the lowest score saved
+    #[default]
+    LowestScore,
+    /// the least recently used
+    LeastRecentlyUsed,
+    /// the first seen
+    Oldest,
 }
 
-fn get_value(lines_backup: &HashMap<String, i64>, key: &String) -> i64 {
-    *lines_backup.get(key).unwrap_or(&0)
+/// Bounds the number of entries of a cache, enforced when saving and cleaning up,
+/// and the number of saves kept in its event log
+#[derive(Clone, Debug)]
+pub struct LimitOptions {
+    pub max_entries: Option<usize>,
+    pub eviction: Eviction,
+    /// past this many saves, the log is folded into the cache file and its oldest half dropped
+    pub max_log_events: usize,
 }
 
-fn trim_newline(s: &mut String) {
-    if s.ends_with('\n') {
-        s.pop();
-        if s.ends_with('\r') {
-            s.pop();
+impl Default for LimitOptions {
+    fn default() -> LimitOptions {
+        LimitOptions {
+            max_entries: None,
+            eviction: Eviction::default(),
+            max_log_events: DEFAULT_MAX_LOG_EVENTS,
         }
     }
 }
 
-fn cleanup(
-    lines_backup: &mut HashMap<String, i64>,
-    cache_file_path: &Path,
-    lines: &Vec<String>,
-) -> Result<(), Box<dyn Error>> {
-    lines_backup.retain(|k, _| lines.contains(k));
-    for line in lines {
-        if !lines_backup.contains_key(line) {
-            lines_backup.insert(line.clone(), 0);
-        }
+pub(crate) fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs() as i64
+}
+
+/// Weight given to hits depending on how long ago the line was last saved,
+/// the same buckets zoxide uses
+fn frecency(entry: &Entry, now: i64) -> f64 {
+    let age = now - entry.last_used;
+    let weight = if age < 60 * 60 {
+        4.0
+    } else if age < 24 * 60 * 60 {
+        2.0
+    } else if age < 7 * 24 * 60 * 60 {
+        0.5
+    } else {
+        0.25
+    };
+    entry.count as f64 * weight
+}
+
+pub(crate) fn score(saved_value: &SavedValue, entry: &Entry, now: i64) -> f64 {
+    match saved_value {
+        SavedValue::Count => entry.count as f64,
+        SavedValue::Timestamp => entry.last_used as f64,
+        SavedValue::Frecency => frecency(entry, now),
     }
-    backup_lines(cache_file_path, lines_backup)?;
-    Ok(())
 }
 
-pub fn get_stdin_lines() -> Result<Vec<String>, Box<dyn Error>> {
-    let stdin = std::io::stdin();
-    Ok(stdin.lock().lines().map(|x| x.unwrap()).collect())
+/// Counts a save of the entry at that time
+pub(crate) fn touch(entry: &mut Entry, saved_value: &SavedValue, time: i64) {
+    if entry.first_seen == 0 {
+        entry.first_seen = time;
+    }
+    entry.last_used = time;
+    entry.count += 1;
+    entry.score = score(saved_value, entry, time);
 }
 
-pub fn get_asc_sorted_lines(
-    mut lines: Vec<String>,
-    lines_backup: &HashMap<String, i64>,
-) -> Result<Vec<String>, Box<dyn Error>> {
-    lines.sort_by(|a, b| {
-        get_value(lines_backup, a)
-            .partial_cmp(&get_value(lines_backup, b))
-            .unwrap()
+/// Moves the entry of a line to another one, adding up with the entry the other line may have,
+/// telling whether there was one to move
+pub(crate) fn rename_entry(entries: &mut LinesBackup, from: &[u8], to: &[u8]) -> bool {
+    let entry = match entries.remove(from) {
+        Some(entry) => entry,
+        None => return false,
+    };
+    let renamed = entries.entry(to.to_vec()).or_insert_with(|| Entry {
+        first_seen: entry.first_seen,
+        ..Entry::new()
     });
-    Ok(lines)
-}
-
-pub fn sort(
-    args: &Args,
-    lines: Vec<String>,
-    lines_backup: &mut HashMap<String, i64>,
-    cache_file_path: &Path,
-) -> Result<Vec<String>, Box<dyn Error>> {
-    let mut lines = get_asc_sorted_lines(lines, lines_backup)?;
-    if args.desc {
-        lines = lines.into_iter().rev().collect();
-    }
-    if args.cleanup {
-        cleanup(lines_backup, cache_file_path, &lines)?;
+    renamed.first_seen = renamed.first_seen.min(entry.first_seen);
+    renamed.last_used = renamed.last_used.max(entry.last_used);
+    renamed.count += entry.count;
+    renamed.score = renamed.score.max(entry.score);
+    true
+}
+
+/// Halves the weight of a line every half-life since its last use, timestamps are left as is
+fn decay(saved_value: &SavedValue, entry: &Entry, now: i64, half_life: Option<i64>) -> f64 {
+    match (saved_value, half_life) {
+        (SavedValue::Timestamp, _) | (_, None) => 1.0,
+        (_, Some(half_life)) => 0.5f64.powf((now - entry.last_used) as f64 / half_life as f64),
     }
-    Ok(lines)
 }
 
-fn update_first_stdin_line(
+pub(crate) fn get_value(
     saved_value: &SavedValue,
-    lines: &[String],
-    lines_backup: &mut HashMap<String, i64>,
-) -> Result<Vec<String>, Box<dyn Error>> {
-    let mut output_lines = Vec::new();
-    if let Some(l) = lines.get(0) {
-        let mut line = l.clone();
-        trim_newline(&mut line);
-        let value = match &saved_value {
-            SavedValue::Count => get_value(lines_backup, &line) + 1,
-            SavedValue::Timestamp => SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .expect("Time went backwards")
-                .as_secs() as i64,
-        };
-        lines_backup.insert(line.to_string(), value);
-        output_lines.push(line)
+    lines_backup: &LinesBackup,
+    key: &[u8],
+    now: i64,
+    half_life: Option<i64>,
+) -> f64 {
+    lines_backup
+        .get(key)
+        .map(|entry| score(saved_value, entry, now) * decay(saved_value, entry, now, half_life))
+        .unwrap_or(0.0)
+}
+
+/// Ends lines by default
+pub const NEWLINE: u8 = b'\n';
+
+/// Ends lines in null mode, for lines which may contain newlines such as paths
+pub const NUL: u8 = b'\0';
+
+/// Splits on the delimiter without requiring UTF-8.
+/// Carriage returns before newlines are dropped
+pub(crate) fn split_lines(input: &[u8], delimiter: u8) -> Vec<Vec<u8>> {
+    let mut lines: Vec<Vec<u8>> = input
+        .split(|b| *b == delimiter)
+        .map(|line| match delimiter {
+            NEWLINE => line.strip_suffix(b"\r").unwrap_or(line).to_vec(),
+            _ => line.to_vec(),
+        })
+        .collect();
+    if input.is_empty() || input.ends_with(&[delimiter]) {
+        lines.pop();
     }
-    Ok(output_lines)
+    lines
 }
 
-pub fn get_cache_file_path(args: &Args) -> Result<PathBuf, Box<dyn Error>> {
-    let cache_dir = dirs::cache_dir().ok_or("did not find cache dir")?;
-    let backup_path = Path::new(&cache_dir).join("baus");
-    create_dir_all(&backup_path)?;
-    Ok(backup_path.join(&args.name))
+/// Reads standard input lines, ended by `NEWLINE` or `NUL`
+pub fn get_stdin_lines(delimiter: u8) -> Result<Vec<Vec<u8>>> {
+    let mut input = Vec::new();
+    std::io::stdin().lock().read_to_end(&mut input)?;
+    Ok(split_lines(&input, delimiter))
 }
 
-pub fn get_lines_backup(cache_file_path: &Path) -> Result<HashMap<String, i64>, Box<dyn Error>> {
-    let lines_backup = HashMap::<String, i64>::new();
-    if !cache_file_path.exists() {
-        backup_lines(cache_file_path, &lines_backup)?;
-    }
-    load_lines(cache_file_path)
+/// Orders lines by the value of their key, decayed by the half-life if any
+pub fn get_asc_sorted_lines(
+    saved_value: &SavedValue,
+    lines: Vec<Vec<u8>>,
+    lines_backup: &LinesBackup,
+    key: &KeyOptions,
+    half_life: Option<i64>,
+) -> Result<Vec<Vec<u8>>> {
+    let now = now();
+    let mut valued_lines: Vec<(f64, Vec<u8>)> = lines
+        .into_iter()
+        .map(|line| {
+            (
+                get_value(saved_value, lines_backup, &key.key(&line), now, half_life),
+                line,
+            )
+        })
+        .collect();
+    valued_lines.sort_by(|a, b| a.0.total_cmp(&b.0));
+    Ok(valued_lines.into_iter().map(|(_, line)| line).collect())
+}
+
+#[cfg(test)]
+fn lines(lines: &[&str]) -> Vec<Vec<u8>> {
+    lines.iter().map(|line| line.as_bytes().to_vec()).collect()
 }
 
-pub fn save(
-    args: &Args,
-    lines: Vec<String>,
-    mut lines_backup: HashMap<String, i64>,
-    cache_file_path: &Path,
-) -> Result<Vec<String>, Box<dyn Error>> {
-    let output_lines = update_first_stdin_line(&args.value, &lines, &mut lines_backup)?;
-    backup_lines(cache_file_path, &lines_backup)?;
-    Ok(output_lines)
+#[cfg(test)]
+fn entry(count: i64, last_used: i64) -> Entry {
+    Entry {
+        first_seen: last_used,
+        last_used,
+        count,
+        score: 0.0,
+    }
 }
 
 #[test]
 fn test_get_asc_sorted_lines() {
     assert_eq!(
         get_asc_sorted_lines(
-            Vec::from(["horse".to_string(), "hamster".to_string()]),
-            &HashMap::from([("horse".to_string(), 2), ("hamster".to_string(), 1)]),
+            &SavedValue::Count,
+            lines(&["horse", "hamster"]),
+            &HashMap::from([
+                (b"horse".to_vec(), entry(2, 0)),
+                (b"hamster".to_vec(), entry(1, 0))
+            ]),
+            &KeyOptions::default(),
+            None,
+        )
+        .unwrap(),
+        lines(&["hamster", "horse"])
+    )
+}
+
+#[test]
+fn test_get_asc_sorted_lines_frecency() {
+    let now = now();
+    assert_eq!(
+        get_asc_sorted_lines(
+            &SavedValue::Frecency,
+            lines(&["horse", "hamster"]),
+            &HashMap::from([
+                (b"horse".to_vec(), entry(10, now - 30 * 24 * 60 * 60)),
+                (b"hamster".to_vec(), entry(2, now))
+            ]),
+            &KeyOptions::default(),
+            None,
         )
         .unwrap(),
-        Vec::from(["hamster", "horse"])
+        lines(&["horse", "hamster"])
     )
 }
 
 #[test]
-fn test_update_first_stdin_line() {
-    let mut cache = HashMap::from([("horse".to_string(), 2), ("hamster".to_string(), 1)]);
+fn test_get_asc_sorted_lines_by_field() {
     assert_eq!(
-        update_first_stdin_line(
+        get_asc_sorted_lines(
             &SavedValue::Count,
-            &Vec::from(["horse".to_string(), "hamster".to_string()]),
-            &mut cache,
+            lines(&["2\thorse", "1\thamster", "3\tcat"]),
+            &HashMap::from([(b"1".to_vec(), entry(2, 0)), (b"2".to_vec(), entry(1, 0))]),
+            &KeyOptions {
+                extractor: KeyExtractor::Field {
+                    delimiter: b"\t".to_vec(),
+                    index: 1
+                },
+                normalizers: Vec::new(),
+            },
+            None,
         )
         .unwrap(),
-        Vec::from(["horse"])
+        lines(&["3\tcat", "2\thorse", "1\thamster"])
+    )
+}
+
+#[test]
+fn test_get_asc_sorted_lines_half_life() {
+    let now = now();
+    let lines_backup = HashMap::from([
+        (b"horse".to_vec(), entry(500, now - 365 * 24 * 60 * 60)),
+        (b"hamster".to_vec(), entry(20, now)),
+    ]);
+    let sort = |half_life| {
+        get_asc_sorted_lines(
+            &SavedValue::Count,
+            lines(&["horse", "hamster"]),
+            &lines_backup,
+            &KeyOptions::default(),
+            half_life,
+        )
+        .unwrap()
+    };
+    assert_eq!(sort(None), lines(&["hamster", "horse"]));
+    assert_eq!(sort(Some(30 * 24 * 60 * 60)), lines(&["horse", "hamster"]));
+}
+
+#[test]
+fn test_split_lines() {
+    assert_eq!(
+        split_lines(b"horse\r\nham\xffster\n\ncat", NEWLINE),
+        Vec::from([
+            b"horse".to_vec(),
+            b"ham\xffster".to_vec(),
+            Vec::new(),
+            b"cat".to_vec()
+        ])
+    );
+    assert_eq!(split_lines(b"horse\n", NEWLINE), lines(&["horse"]));
+    assert!(split_lines(b"", NEWLINE).is_empty());
+    assert_eq!(
+        split_lines(b"my\nhorse\r\0hamster\0", NUL),
+        lines(&["my\nhorse\r", "hamster"])
     );
-    assert_eq!(cache.get("horse"), Some(&3));
-    assert_eq!(cache.get("hamster"), Some(&1))
 }
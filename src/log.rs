@@ -0,0 +1,270 @@
+use crate::cache::{decode_key, encode_key, sibling_path, write_atomically};
+use crate::error::Result;
+use crate::{rename_entry, touch, Cache, Format, SavedValue};
+use miniserde::json;
+use miniserde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+
+/// Saves kept in the event log when no maximum is given
+pub const DEFAULT_MAX_LOG_EVENTS: usize = 1000;
+
+/// What an event did to the entry of its line
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum Change {
+    /// the line was saved
+    #[default]
+    Save,
+    /// the entry was forgotten, by removing, blocking, resetting or cleaning up
+    Remove,
+    /// the entry was moved to that line, adding up with the entry it may have had
+    Rename(Vec<u8>),
+}
+
+/// One change to the entry of a line, a save most of the time, as appended to the event log
+/// of a cache
+#[derive(Clone, Debug, PartialEq)]
+pub struct Event {
+    /// number of the event, counted from 1 since the cache was created
+    pub seq: u64,
+    /// unix time of the event
+    pub time: i64,
+    /// value the line was saved with
+    pub mode: SavedValue,
+    pub key: Vec<u8>,
+    /// where the line was saved from, such as a directory, when it was given
+    pub context: Option<String>,
+    pub change: Change,
+}
+
+/// Layout of an event on its line of the log, with keys encoded by `encode_key`.
+/// Saves have no change, which keeps the logs of older baus readable
+#[derive(Serialize, Deserialize)]
+struct EventLine {
+    seq: u64,
+    time: i64,
+    mode: SavedValue,
+    key: String,
+    context: Option<String>,
+    /// `remove` or `rename`
+    change: Option<String>,
+    /// line the entry was renamed to
+    to: Option<String>,
+}
+
+/// Path of the event log of a cache. Caches in the lines format keep one log per device,
+/// since the sequence numbers of a log only count the saves of the device it belongs to
+pub(crate) fn log_path(cache_file_path: &Path, format: Format, device: &str) -> PathBuf {
+    match format {
+        Format::Json | Format::Sqlite => sibling_path(cache_file_path, ".log"),
+        Format::Lines => {
+            let device = device.replace(['/', '\\'], "_");
+            sibling_path(cache_file_path, &format!(".log-{}", device))
+        }
+    }
+}
+
+fn event_lines(events: &[Event]) -> String {
+    events
+        .iter()
+        .map(|event| {
+            let (change, to) = match &event.change {
+                Change::Save => (None, None),
+                Change::Remove => (Some("remove".to_string()), None),
+                Change::Rename(to) => (Some("rename".to_string()), Some(encode_key(to))),
+            };
+            let line = EventLine {
+                seq: event.seq,
+                time: event.time,
+                mode: event.mode.clone(),
+                key: encode_key(&event.key),
+                context: event.context.clone(),
+                change,
+                to,
+            };
+            json::to_string(&line) + "\n"
+        })
+        .collect()
+}
+
+/// Reads the events of the log, oldest first, skipping lines which do not parse
+/// such as the last one of a run killed while appending it, or changes this baus does not know
+pub(crate) fn read_events(log_file_path: &Path) -> Result<Vec<Event>> {
+    let mut contents = Vec::new();
+    match File::open(log_file_path) {
+        Ok(mut file) => file.read_to_end(&mut contents)?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    Ok(contents
+        .split(|b| *b == b'\n')
+        .filter_map(|line| json::from_str::<EventLine>(&String::from_utf8_lossy(line)).ok())
+        .filter_map(|line| {
+            let change = match (line.change.as_deref(), &line.to) {
+                (None, _) => Change::Save,
+                (Some("remove"), _) => Change::Remove,
+                (Some("rename"), Some(to)) => Change::Rename(decode_key(to)),
+                _ => return None,
+            };
+            Some(Event {
+                seq: line.seq,
+                time: line.time,
+                mode: line.mode,
+                key: decode_key(&line.key),
+                context: line.context,
+                change,
+            })
+        })
+        .collect())
+}
+
+/// Appends the events in one write synced before returning, after ending the line
+/// a killed run may have left unfinished so that only that one is lost
+pub(crate) fn append_events(log_file_path: &Path, events: &[Event]) -> Result<()> {
+    if events.is_empty() {
+        return Ok(());
+    }
+    let mut file = OpenOptions::new()
+        .read(true)
+        .append(true)
+        .create(true)
+        .open(log_file_path)?;
+    let mut lines = event_lines(events);
+    if file.metadata()?.len() > 0 {
+        let mut last = [0];
+        file.seek(SeekFrom::End(-1))?;
+        file.read_exact(&mut last)?;
+        if last[0] != b'\n' {
+            lines.insert(0, '\n');
+        }
+    }
+    file.write_all(lines.as_bytes())?;
+    file.sync_data()?;
+    Ok(())
+}
+
+/// Replaces the log with these events, removing it when there are none
+pub(crate) fn write_events(log_file_path: &Path, events: &[Event]) -> Result<()> {
+    if events.is_empty() {
+        return match std::fs::remove_file(log_file_path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        };
+    }
+    write_atomically(log_file_path, event_lines(events).as_bytes())
+}
+
+/// Changes the entries as the event did, counting a save with that value
+pub(crate) fn change_entries(cache: &mut Cache, event: &Event, saved_value: &SavedValue) {
+    match &event.change {
+        Change::Save if cache.blocked.contains(&event.key) => (),
+        Change::Save => {
+            let entry = cache.entries.entry(event.key.clone()).or_default();
+            touch(entry, saved_value, event.time);
+        }
+        Change::Remove => {
+            cache.entries.remove(&event.key);
+        }
+        Change::Rename(to) => {
+            rename_entry(&mut cache.entries, &event.key, to);
+        }
+    }
+}
+
+/// Folds an event into the entries, unless the cache already holds it
+pub(crate) fn apply_event(cache: &mut Cache, event: &Event) {
+    if event.seq <= cache.last_event {
+        return;
+    }
+    cache.last_event = event.seq;
+    change_entries(cache, event, &event.mode);
+    if event.change == Change::Save {
+        cache.mode.get_or_insert_with(|| event.mode.clone());
+    }
+}
+
+#[cfg(test)]
+use crate::Entry;
+#[cfg(test)]
+use std::collections::HashMap;
+
+#[test]
+fn test_event_log() {
+    let dir = crate::cache::test_dir("log");
+    let log_file_path = log_path(&dir.join("animals"), Format::Json, "");
+    let event = |seq, key: &[u8]| Event {
+        seq,
+        time: 100 + seq as i64,
+        mode: SavedValue::Count,
+        key: key.to_vec(),
+        context: Some("/home".to_string()),
+        change: Change::Save,
+    };
+    let changed = |seq, key: &[u8], change| Event {
+        context: None,
+        change,
+        ..event(seq, key)
+    };
+    std::fs::create_dir_all(&dir).unwrap();
+    append_events(&log_file_path, &[event(1, b"horse")]).unwrap();
+    // a run killed halfway through appending its line
+    std::fs::OpenOptions::new()
+        .append(true)
+        .open(&log_file_path)
+        .unwrap()
+        .write_all(br#"{"seq":2,"ti"#)
+        .unwrap();
+    append_events(&log_file_path, &[event(3, b"h\xff"), event(4, b"horse")]).unwrap();
+    append_events(
+        &log_file_path,
+        &[
+            changed(5, b"cow", Change::Rename(b"h\xff".to_vec())),
+            changed(6, b"pig", Change::Remove),
+        ],
+    )
+    .unwrap();
+    let events = read_events(&log_file_path).unwrap();
+    write_events(&log_file_path, &[]).unwrap();
+    let removed = !log_file_path.exists();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(
+        events,
+        Vec::from([
+            event(1, b"horse"),
+            event(3, b"h\xff"),
+            event(4, b"horse"),
+            changed(5, b"cow", Change::Rename(b"h\xff".to_vec())),
+            changed(6, b"pig", Change::Remove),
+        ])
+    );
+    assert!(removed);
+    let mut cache = Cache {
+        last_event: 1,
+        entries: HashMap::from([
+            (
+                b"cow".to_vec(),
+                Entry {
+                    count: 2,
+                    first_seen: 90,
+                    last_used: 90,
+                    ..Entry::new()
+                },
+            ),
+            (b"pig".to_vec(), Entry::new()),
+        ]),
+        ..Cache::default()
+    };
+    for event in &events {
+        apply_event(&mut cache, event);
+    }
+    assert_eq!(cache.last_event, 6);
+    assert!(!cache.entries.contains_key(&b"pig"[..]));
+    assert!(!cache.entries.contains_key(&b"cow"[..]));
+    assert_eq!(cache.entries.get(&b"h\xff"[..]).unwrap().count, 3);
+    assert_eq!(cache.mode, Some(SavedValue::Count));
+    assert_eq!(cache.entries.get(&b"horse"[..]).unwrap().count, 1);
+    assert_eq!(cache.entries.get(&b"horse"[..]).unwrap().last_used, 104);
+    assert_eq!(cache.entries.get(&b"h\xff"[..]).unwrap().first_seen, 90);
+}
@@ -1,17 +1,238 @@
-use baus::{get_cache_file_path, get_lines_backup, get_stdin_lines, save, sort, Action, Args};
-use clap::Parser;
-use std::error::Error;
-fn main() -> Result<(), Box<dyn Error>> {
-    let args = Args::parse();
-    let cache_file_path = get_cache_file_path(&args)?;
-    let mut lines_backup = get_lines_backup(&cache_file_path)?;
-    let lines = get_stdin_lines()?;
-    let output_lines = match &args.action {
-        Action::Sort => sort(&args, lines, &mut lines_backup, &cache_file_path)?,
-        Action::Save => save(&args, lines, lines_backup, &cache_file_path)?,
+use baus::cli::{mode, os_bytes, Action, Args};
+use baus::{get_stdin_lines, list_caches, CacheInfo, Config, KeyOptions, Store};
+use clap::{CommandFactory, ErrorKind, Parser};
+use std::ffi::OsString;
+use std::io::{BufWriter, Read, Write};
+use std::path::Path;
+
+/// Keys given on the command line, normalized as the keys of saved lines are
+fn normalized_keys(keys: &[OsString], key: &KeyOptions) -> Vec<Vec<u8>> {
+    keys.iter()
+        .map(|k| key.normalize(&os_bytes(k)).into_owned())
+        .collect()
+}
+
+fn cache_line(cache: &CacheInfo) -> Vec<u8> {
+    let entries = cache
+        .entries
+        .map_or_else(|| "-".to_string(), |entries| entries.to_string());
+    format!("{}\t{}\t{}", cache.name, entries, cache.modified).into_bytes()
+}
+
+fn write_lines(lines: &[Vec<u8>], delimiter: u8) -> std::io::Result<()> {
+    let mut stdout = BufWriter::new(std::io::stdout().lock());
+    for line in lines {
+        stdout.write_all(line)?;
+        stdout.write_all(&[delimiter])?;
+    }
+    stdout.flush()
+}
+
+/// Contents of the file, or of standard input when there is none or it is `-`
+fn read_input(file: &Path) -> std::io::Result<Vec<u8>> {
+    if file != Path::new("-") {
+        return std::fs::read(file);
+    }
+    let mut input = Vec::new();
+    std::io::stdin().lock().read_to_end(&mut input)?;
+    Ok(input)
+}
+
+/// Warns about the cache files the store could not parse and replaced by empty ones
+fn warn_set_aside(store: &mut Store) {
+    for set_aside in store.take_set_aside() {
+        eprintln!(
+            "baus: warning: could not parse {}, moved it to {} and started an empty cache",
+            set_aside.path.display(),
+            set_aside.moved_to.display()
+        );
+    }
+}
+
+/// Runs the action, returning the exit code baus should end with
+fn run(args: Args) -> baus::Result<i32> {
+    let delimiter = args.delimiter();
+    if args.caches {
+        let caches = list_caches(&args.cache_dir()?)?;
+        write_lines(
+            &caches.iter().map(cache_line).collect::<Vec<_>>(),
+            delimiter,
+        )?;
+        return Ok(0);
+    }
+    let (name, action) = match (&args.name, &args.action) {
+        (Some(name), Some(action)) => (name, action),
+        _ => Args::command()
+            .error(ErrorKind::MissingSubcommand, "an action is required")
+            .exit(),
+    };
+    // stdin is read before locking, a save waiting for its picker must not block the sort feeding it
+    let settings = Config::load()?.settings(name);
+    let key = args.key.options(&settings)?;
+    let lines = if action.reads_stdin() {
+        get_stdin_lines(delimiter)?
+    } else {
+        Vec::new()
     };
-    for line in &output_lines {
-        println!("{}", line);
+    let input = match action {
+        Action::Import { input, .. } => read_input(input.as_deref().unwrap_or(Path::new("-")))?,
+        _ => Vec::new(),
+    };
+    let mut store = Store::open_path(args.cache_file_path(name)?)?
+        .with_key(key.clone())
+        .with_aging(args.aging.options(&settings))
+        .with_limit(args.limit.options(&settings))
+        .with_context(action.context());
+    warn_set_aside(&mut store);
+    let recorded = store.cache().mode.clone();
+    let (output_lines, exit_code) = match action {
+        Action::Sort {
+            value,
+            sort: sort_args,
+            explain,
+        } => {
+            let value = &mode(value, &settings, &recorded);
+            let lines = store.sort(lines, &sort_args.options(value, &settings))?;
+            if *explain {
+                (store.explain(lines, value), 0)
+            } else {
+                (lines, 0)
+            }
+        }
+        Action::Save {
+            value,
+            save: save_args,
+        } => {
+            let value = &mode(value, &settings, &recorded);
+            (store.save(lines, save_args.max_lines(), value)?, 0)
+        }
+        Action::Pick {
+            value,
+            sort: sort_args,
+            save: save_args,
+            picker,
+        } => store.pick(
+            lines,
+            &sort_args.options(&mode(value, &settings, &recorded), &settings),
+            save_args.max_lines(),
+            picker,
+            delimiter,
+        )?,
+        Action::List {
+            value,
+            desc,
+            format,
+        } => (
+            store.list(&mode(value, &settings, &recorded), *desc, format),
+            0,
+        ),
+        Action::Show {
+            value,
+            key: show_key,
+        } => {
+            let value = &mode(value, &settings, &recorded);
+            (store.show(&key.normalize(&os_bytes(show_key)), value)?, 0)
+        }
+        Action::Remove { keys, pattern } => {
+            let pattern = pattern
+                .as_deref()
+                .map(|pattern| key.normalize(&os_bytes(pattern)).into_owned());
+            (
+                store.remove(&normalized_keys(keys, &key), pattern.as_deref())?,
+                0,
+            )
+        }
+        Action::Export { format } => {
+            let mut stdout = std::io::stdout().lock();
+            stdout.write_all(&store.export(*format))?;
+            stdout.flush()?;
+            (Vec::new(), 0)
+        }
+        Action::Import {
+            input: file,
+            format,
+            from,
+            merge,
+        } => {
+            let path = file.as_deref().unwrap_or(Path::new("-"));
+            match from {
+                Some(history) => store.import_history(path, &input, *history, *merge)?,
+                None => store.import(path, &input, *format, *merge)?,
+            }
+            (Vec::new(), 0)
+        }
+        Action::Merge { others } => {
+            let merged = store.merge(others);
+            warn_set_aside(&mut store);
+            merged?;
+            (Vec::new(), 0)
+        }
+        Action::Log => (store.log(), 0),
+        Action::Compact => {
+            store.compact()?;
+            (Vec::new(), 0)
+        }
+        Action::Replay { value } => {
+            store.replay(&mode(value, &settings, &recorded))?;
+            (Vec::new(), 0)
+        }
+        Action::Migrate { to } => {
+            store.convert(*to)?;
+            (Vec::new(), 0)
+        }
+        Action::Reset => {
+            store.reset()?;
+            (Vec::new(), 0)
+        }
+        Action::Rename { from, to } => {
+            store.rename(
+                &key.normalize(&os_bytes(from)),
+                &key.normalize(&os_bytes(to)),
+            )?;
+            (Vec::new(), 0)
+        }
+        Action::Pin { keys, .. } if keys.is_empty() => (store.pins(), 0),
+        Action::Pin { keys, at } => {
+            store.pin(&normalized_keys(keys, &key), *at)?;
+            (Vec::new(), 0)
+        }
+        Action::Unpin { keys } => {
+            store.unpin(&normalized_keys(keys, &key))?;
+            (Vec::new(), 0)
+        }
+        Action::Block { keys } if keys.is_empty() => (store.blocked(), 0),
+        Action::Block { keys } => {
+            store.block(&normalized_keys(keys, &key))?;
+            (Vec::new(), 0)
+        }
+        Action::Unblock { keys } => {
+            store.unblock(&normalized_keys(keys, &key))?;
+            (Vec::new(), 0)
+        }
+        Action::Set {
+            value,
+            key: set_key,
+            score,
+        } => {
+            store.set(
+                &key.normalize(&os_bytes(set_key)),
+                *score,
+                &mode(value, &settings, &recorded),
+            )?;
+            (Vec::new(), 0)
+        }
+    };
+    write_lines(&output_lines, delimiter)?;
+    Ok(exit_code)
+}
+
+fn main() {
+    match run(Args::parse().with_legacy_flags()) {
+        Ok(0) => (),
+        Ok(exit_code) => std::process::exit(exit_code),
+        Err(e) => {
+            eprintln!("baus: {}", e);
+            std::process::exit(1);
+        }
     }
-    Ok(())
 }
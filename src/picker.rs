@@ -0,0 +1,49 @@
+use crate::error::{Error, Result};
+use crate::split_lines;
+use std::io::prelude::*;
+use std::process::{Command, Stdio};
+
+fn picker_command(picker: &str) -> Command {
+    let (shell, flag) = if cfg!(windows) {
+        ("cmd", "/C")
+    } else {
+        ("sh", "-c")
+    };
+    let mut command = Command::new(shell);
+    command.arg(flag).arg(picker);
+    command
+}
+
+/// Runs the picker with the lines on its standard input,
+/// returning the lines it printed and its exit code
+pub(crate) fn run_picker(
+    picker: &str,
+    lines: &[Vec<u8>],
+    delimiter: u8,
+) -> Result<(Vec<Vec<u8>>, i32)> {
+    let mut child = picker_command(picker)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|source| Error::Picker {
+            command: picker.to_string(),
+            source,
+        })?;
+    let mut stdin = child.stdin.take().expect("picker stdin is piped");
+    let input: Vec<u8> = lines
+        .iter()
+        .flat_map(|line| line.iter().chain([&delimiter]))
+        .copied()
+        .collect();
+    // written from another thread so a picker printing before it read everything cannot block
+    let writer = std::thread::spawn(move || {
+        // the picker may exit without reading all of its input
+        let _ = stdin.write_all(&input);
+    });
+    let output = child.wait_with_output()?;
+    let _ = writer.join();
+    Ok((
+        split_lines(&output.stdout, delimiter),
+        output.status.code().unwrap_or(1),
+    ))
+}
@@ -0,0 +1,214 @@
+use crate::cache::{from_value_name, value_name};
+use crate::error::{Error, Result};
+use crate::{Cache, Entry, Pin, SavedValue, CACHE_VERSION};
+use rusqlite::{params, Connection, OpenFlags, Row, TransactionBehavior};
+use std::path::Path;
+use std::time::Duration;
+
+/// Keys are blobs, so lines need no encoding, and settings of the cache are text in `meta`
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS meta (key TEXT PRIMARY KEY, value BLOB NOT NULL);
+CREATE TABLE IF NOT EXISTS entries (
+    key BLOB PRIMARY KEY,
+    first_seen INTEGER NOT NULL,
+    last_used INTEGER NOT NULL,
+    count INTEGER NOT NULL,
+    score REAL NOT NULL
+);
+CREATE TABLE IF NOT EXISTS pinned (key BLOB PRIMARY KEY, pin BLOB NOT NULL);
+CREATE TABLE IF NOT EXISTS blocked (key BLOB PRIMARY KEY);
+";
+
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Error {
+        Error::Sqlite(e.to_string())
+    }
+}
+
+/// Opens the database, creating its tables if needed,
+/// and waits for other runs writing to it instead of failing
+fn open(path: &Path) -> Result<Connection> {
+    let connection = Connection::open(path)?;
+    connection.busy_timeout(Duration::from_secs(5))?;
+    connection.execute_batch(SCHEMA)?;
+    Ok(connection)
+}
+
+/// Opens the database without writing to it, not even to create its tables
+fn open_read_only(path: &Path) -> Result<Connection> {
+    let connection = Connection::open_with_flags(
+        path,
+        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )?;
+    connection.busy_timeout(Duration::from_secs(5))?;
+    Ok(connection)
+}
+
+/// Blob of a column, NULL being read as empty
+fn blob(row: &Row, index: usize) -> rusqlite::Result<Vec<u8>> {
+    Ok(row.get::<_, Option<Vec<u8>>>(index)?.unwrap_or_default())
+}
+
+/// Loads a SQLite cache, creating its tables if needed
+pub(crate) fn load(path: &Path) -> Result<Cache> {
+    read(path, &open(path)?)
+}
+
+/// Loads a SQLite cache without writing to it
+pub(crate) fn load_read_only(path: &Path) -> Result<Cache> {
+    read(path, &open_read_only(path)?)
+}
+
+fn read(path: &Path, connection: &Connection) -> Result<Cache> {
+    let meta = connection
+        .prepare("SELECT key, value FROM meta")?
+        .query_map([], |row| Ok((blob(row, 0)?, blob(row, 1)?)))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    let mut cache = Cache::default();
+    for (key, value) in meta {
+        match &key[..] {
+            b"version" => {
+                let version = String::from_utf8_lossy(&value).parse().unwrap_or(0);
+                if version > CACHE_VERSION {
+                    return Err(Error::UnsupportedVersion {
+                        path: path.to_path_buf(),
+                        version,
+                    });
+                }
+            }
+            b"mode" => cache.mode = from_value_name::<SavedValue>(&value),
+            b"last_event" => {
+                cache.last_event = String::from_utf8_lossy(&value).parse().unwrap_or(0)
+            }
+            b"trimmed" => cache.trimmed = String::from_utf8_lossy(&value).parse().unwrap_or(0),
+            b"unlogged" => cache.unlogged = value == b"true",
+            _ => (),
+        }
+    }
+    cache.entries = connection
+        .prepare("SELECT key, first_seen, last_used, count, score FROM entries")?
+        .query_map([], |row| {
+            let entry = Entry {
+                first_seen: row.get(1)?,
+                last_used: row.get(2)?,
+                count: row.get(3)?,
+                score: row.get(4)?,
+            };
+            Ok((blob(row, 0)?, entry))
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+    cache.pinned = connection
+        .prepare("SELECT key, pin FROM pinned")?
+        .query_map([], |row| Ok((blob(row, 0)?, blob(row, 1)?)))?
+        .collect::<rusqlite::Result<Vec<_>>>()?
+        .into_iter()
+        .filter_map(|(key, pin)| Some((key, from_value_name::<Pin>(&pin)?)))
+        .collect();
+    cache.blocked = connection
+        .prepare("SELECT key FROM blocked")?
+        .query_map([], |row| blob(row, 0))?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(cache)
+}
+
+/// Writes what changed since the cache was loaded, row by row in one transaction
+pub(crate) fn write(path: &Path, saved: &Cache, cache: &Cache) -> Result<()> {
+    let mut connection = open(path)?;
+    // rolled back when dropped before being committed, if any change fails
+    let transaction = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    {
+        let mut set_meta =
+            transaction.prepare("INSERT OR REPLACE INTO meta (key, value) VALUES (?1, ?2)")?;
+        let version = CACHE_VERSION.to_string();
+        let last_event = cache.last_event.to_string();
+        let trimmed = cache.trimmed.to_string();
+        let unlogged = cache.unlogged.to_string();
+        for (key, value) in [
+            ("version", &version),
+            ("last_event", &last_event),
+            ("trimmed", &trimmed),
+            ("unlogged", &unlogged),
+        ] {
+            set_meta.execute(params![key.as_bytes(), value.as_bytes()])?;
+        }
+        if let Some(mode) = &cache.mode {
+            set_meta.execute(params![&b"mode"[..], value_name(mode)])?;
+        } else if saved.mode.is_some() {
+            transaction.execute("DELETE FROM meta WHERE key = 'mode'", [])?;
+        }
+        let mut upsert = transaction.prepare(
+            "INSERT INTO entries (key, first_seen, last_used, count, score)
+             VALUES (?1, ?2, ?3, ?4, ?5)
+             ON CONFLICT (key) DO UPDATE SET first_seen = excluded.first_seen,
+             last_used = excluded.last_used, count = excluded.count, score = excluded.score",
+        )?;
+        for (key, entry) in &cache.entries {
+            if saved.entries.get(key) != Some(entry) {
+                upsert.execute(params![
+                    key,
+                    entry.first_seen,
+                    entry.last_used,
+                    entry.count,
+                    entry.score
+                ])?;
+            }
+        }
+        let mut delete = transaction.prepare("DELETE FROM entries WHERE key = ?1")?;
+        for key in saved.entries.keys() {
+            if !cache.entries.contains_key(key) {
+                delete.execute(params![key])?;
+            }
+        }
+        let mut pin =
+            transaction.prepare("INSERT OR REPLACE INTO pinned (key, pin) VALUES (?1, ?2)")?;
+        for (key, value) in &cache.pinned {
+            if saved.pinned.get(key) != Some(value) {
+                pin.execute(params![key, value_name(value)])?;
+            }
+        }
+        let mut unpin = transaction.prepare("DELETE FROM pinned WHERE key = ?1")?;
+        for key in saved.pinned.keys() {
+            if !cache.pinned.contains_key(key) {
+                unpin.execute(params![key])?;
+            }
+        }
+        let mut block = transaction.prepare("INSERT OR IGNORE INTO blocked (key) VALUES (?1)")?;
+        for key in cache.blocked.difference(&saved.blocked) {
+            block.execute(params![key])?;
+        }
+        let mut unblock = transaction.prepare("DELETE FROM blocked WHERE key = ?1")?;
+        for key in saved.blocked.difference(&cache.blocked) {
+            unblock.execute(params![key])?;
+        }
+    }
+    transaction.commit()?;
+    Ok(())
+}
+
+#[test]
+fn test_sqlite_round_trip() {
+    let dir = crate::cache::test_dir("sqlite");
+    let path = dir.join("animals");
+    let mut cache = load(&path).unwrap();
+    let empty = cache.clone();
+    cache.mode = Some(SavedValue::Frecency);
+    cache.entries.insert(b"horse".to_vec(), crate::entry(2, 10));
+    cache
+        .entries
+        .insert(b"h\xff\0".to_vec(), crate::entry(1, 20));
+    cache.pinned.insert(b"horse".to_vec(), Pin::Bottom);
+    cache.blocked.insert(b"cat".to_vec());
+    write(&path, &empty, &cache).unwrap();
+    let saved = load(&path).unwrap();
+    let mut changed = saved.clone();
+    changed.entries.remove(&b"horse"[..]);
+    changed.entries.get_mut(&b"h\xff\0"[..]).unwrap().count = 5;
+    changed.pinned.clear();
+    changed.blocked.clear();
+    write(&path, &saved, &changed).unwrap();
+    let reloaded = load_read_only(&path).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(empty, Cache::default());
+    assert_eq!(saved, cache);
+    assert_eq!(reloaded, changed);
+}
@@ -0,0 +1,1428 @@
+use crate::cache::{
+    convert_cache, encode_key, get_cache_file_path, get_format, load_snapshot, lock_cache,
+    save_cache, SetAside,
+};
+use crate::error::{Error, Result};
+use crate::exchange::{export, import, merge_entry};
+use crate::history::import_history;
+use crate::log::{
+    append_events, apply_event, change_entries, log_path, read_events, write_events, Change, Event,
+};
+use crate::picker::run_picker;
+use crate::sync::{devices_of, get_device, merge_devices, total_entries};
+use crate::{
+    get_asc_sorted_lines, get_value, now, rename_entry, score, touch, AgingOptions, Blocked, Cache,
+    Entry, Eviction, ExchangeFormat, Format, History, KeyOptions, LimitOptions, LinesBackup,
+    ListFormat, Merge, Pin, SavedValue, SortOptions,
+};
+use miniserde::json;
+use miniserde::Serialize;
+use std::collections::HashMap;
+use std::fs::create_dir_all;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+/// Entry as printed by list
+#[derive(Serialize)]
+struct ListedEntry {
+    /// encoded as in the cache file
+    key: String,
+    value: f64,
+    count: i64,
+    first_seen: i64,
+    last_used: i64,
+    score: f64,
+}
+
+/// A cache, locked for as long as it is open.
+/// Every change is written back to the cache file before returning
+pub struct Store {
+    path: PathBuf,
+    format: Format,
+    /// event log of the cache, which depends on its format
+    log_path: PathBuf,
+    /// what the saves of lines caches are counted under
+    device: String,
+    cache: Cache,
+    /// the cache as last written, to only write what changed in SQLite caches
+    saved: Cache,
+    /// saves and other changes kept in the event log, oldest first
+    events: Vec<Event>,
+    /// logged with the saves
+    context: Option<String>,
+    key: KeyOptions,
+    aging: AgingOptions,
+    limit: LimitOptions,
+    /// cache files which could not be parsed, not yet taken by the caller
+    set_aside: Vec<SetAside>,
+    _lock: File,
+}
+
+fn cleanup(lines_backup: &mut LinesBackup, lines: &[Vec<u8>], key: &KeyOptions) {
+    let keys: Vec<Vec<u8>> = lines
+        .iter()
+        .map(|line| key.key(line).into_owned())
+        .collect();
+    lines_backup.retain(|k, _| keys.contains(k));
+    for key in keys {
+        lines_backup.entry(key).or_default();
+    }
+}
+
+/// Scales every count down once they add up past the maximum, dropping the entries left too low,
+/// telling whether it did
+fn age(lines_backup: &mut LinesBackup, aging: &AgingOptions) -> bool {
+    let max_total = match aging.max_total {
+        Some(max_total) => max_total,
+        None => return false,
+    };
+    let total: i64 = lines_backup.values().map(|entry| entry.count).sum();
+    if total <= max_total {
+        return false;
+    }
+    let factor = 0.9 * max_total as f64 / total as f64;
+    for entry in lines_backup.values_mut() {
+        entry.count = (entry.count as f64 * factor) as i64;
+    }
+    lines_backup.retain(|_, entry| entry.count >= aging.min_count);
+    true
+}
+
+/// Evicts entries until the cache fits, the lines just saved or sorted going last,
+/// telling whether any was
+fn evict(lines_backup: &mut LinesBackup, limit: &LimitOptions, kept: &[Vec<u8>]) -> bool {
+    let max_entries = match limit.max_entries {
+        Some(max_entries) if lines_backup.len() > max_entries => max_entries,
+        _ => return false,
+    };
+    let mut candidates: Vec<(&Vec<u8>, &Entry)> = lines_backup.iter().collect();
+    candidates.sort_by(|a, b| {
+        let order = match limit.eviction {
+            Eviction::LowestScore => a.1.score.total_cmp(&b.1.score),
+            Eviction::LeastRecentlyUsed => a.1.last_used.cmp(&b.1.last_used),
+            Eviction::Oldest => a.1.first_seen.cmp(&b.1.first_seen),
+        };
+        kept.contains(a.0)
+            .cmp(&kept.contains(b.0))
+            .then(order)
+            .then_with(|| a.0.cmp(b.0))
+    });
+    let evicted: Vec<Vec<u8>> = candidates[..lines_backup.len() - max_entries]
+        .iter()
+        .map(|(key, _)| key.to_vec())
+        .collect();
+    for key in evicted {
+        lines_backup.remove(&key);
+    }
+    true
+}
+
+/// Moves the lines of keys pinned to the top before the others and those pinned to the bottom after
+fn place_pinned(
+    lines: Vec<Vec<u8>>,
+    pinned: &HashMap<Vec<u8>, Pin>,
+    key: &KeyOptions,
+) -> Vec<Vec<u8>> {
+    if pinned.is_empty() {
+        return lines;
+    }
+    let (mut top, mut middle, mut bottom) = (Vec::new(), Vec::new(), Vec::new());
+    for line in lines {
+        match pinned.get(key.key(&line).as_ref()) {
+            Some(Pin::Top) => top.push(line),
+            Some(Pin::Bottom) => bottom.push(line),
+            None => middle.push(line),
+        }
+    }
+    top.into_iter().chain(middle).chain(bottom).collect()
+}
+
+/// Saves each line under its key at that time
+fn update_stdin_lines(
+    saved_value: &SavedValue,
+    lines: &[Vec<u8>],
+    lines_backup: &mut LinesBackup,
+    key: &KeyOptions,
+    now: i64,
+) -> Vec<Vec<u8>> {
+    let mut output_lines = Vec::new();
+    for line in lines {
+        let entry = lines_backup.entry(key.key(line).into_owned()).or_default();
+        touch(entry, saved_value, now);
+        output_lines.push(line.clone())
+    }
+    output_lines
+}
+
+/// Length of the UTF-8 character starting the bytes, one for anything else
+fn char_len(text: &[u8]) -> usize {
+    (1..=text.len().min(4))
+        .find(|len| std::str::from_utf8(&text[..*len]).is_ok())
+        .unwrap_or(1)
+}
+
+/// Matches a glob where `*` stands for any sequence of bytes and `?` for any one character
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // position of the last star seen and of the text it was tried against
+    let mut backtrack = None;
+    while t < text.len() {
+        if p < pattern.len() && pattern[p] == b'?' {
+            p += 1;
+            t += char_len(&text[t..]);
+        } else if p < pattern.len() && pattern[p] == b'*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if p < pattern.len() && pattern[p] == text[t] {
+            p += 1;
+            t += 1;
+        } else if let Some((star, star_t)) = backtrack {
+            p = star + 1;
+            t = star_t + 1;
+            backtrack = Some((star, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == b'*')
+}
+
+/// Joins fields with tabs
+fn tab_separated(fields: &[&[u8]]) -> Vec<u8> {
+    fields.join(&b'\t')
+}
+
+impl Store {
+    /// Opens the cache with that name in the baus cache directory
+    pub fn open(name: &str) -> Result<Store> {
+        Store::open_path(get_cache_file_path(name)?)
+    }
+
+    /// Opens the cache stored in that file, which is created if needed
+    pub fn open_path(path: impl Into<PathBuf>) -> Result<Store> {
+        Store::open_path_as(path, &get_device())
+    }
+
+    /// Opens the cache stored in that file as that device, which the saves of lines caches
+    /// are counted under
+    pub fn open_path_as(path: impl Into<PathBuf>, device: &str) -> Result<Store> {
+        let path = path.into();
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            create_dir_all(parent)?;
+        }
+        let lock = lock_cache(&path)?;
+        let format = get_format(&path)?;
+        let (mut cache, set_aside) = load_snapshot(&path, device)?;
+        let saved = match format {
+            Format::Json | Format::Lines => Cache::default(),
+            Format::Sqlite => cache.clone(),
+        };
+        let log_path = log_path(&path, format, device);
+        let events = read_events(&log_path)?;
+        for event in &events {
+            apply_event(&mut cache, event);
+        }
+        Ok(Store {
+            path,
+            format,
+            log_path,
+            device: device.to_string(),
+            cache,
+            saved,
+            events,
+            context: None,
+            key: KeyOptions::default(),
+            aging: AgingOptions::default(),
+            limit: LimitOptions::default(),
+            set_aside: set_aside.into_iter().collect(),
+            _lock: lock,
+        })
+    }
+
+    /// Saves and sorts lines by the key extracted from them instead of the whole line
+    pub fn with_key(mut self, key: KeyOptions) -> Store {
+        self.key = key;
+        self
+    }
+
+    /// Ages counts when saving and decays them when sorting
+    pub fn with_aging(mut self, aging: AgingOptions) -> Store {
+        self.aging = aging;
+        self
+    }
+
+    /// Evicts entries past the maximum when saving and cleaning up
+    pub fn with_limit(mut self, limit: LimitOptions) -> Store {
+        self.limit = limit;
+        self
+    }
+
+    /// Logs saves along with where they were made from, such as a directory
+    pub fn with_context(mut self, context: Option<String>) -> Store {
+        self.context = context;
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn entries(&self) -> &LinesBackup {
+        &self.cache.entries
+    }
+
+    /// The entries along with the pinned and blocked keys
+    pub fn cache(&self) -> &Cache {
+        &self.cache
+    }
+
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    /// Saves and other changes kept in the event log, oldest first
+    pub fn events(&self) -> &[Event] {
+        &self.events
+    }
+
+    /// Cache files which could not be parsed and were replaced by empty ones since this was
+    /// last called, the cache itself when opening it or the ones merged in, to warn about
+    pub fn take_set_aside(&mut self) -> Vec<SetAside> {
+        std::mem::take(&mut self.set_aside)
+    }
+
+    /// Writes the cache file, which then holds every logged change
+    fn write(&mut self) -> Result<()> {
+        if self.format == Format::Lines {
+            // kept as written, for the lines removed from the entries to be told apart later
+            self.cache.devices = devices_of(&self.cache, &self.device);
+        }
+        save_cache(
+            &self.path,
+            self.format,
+            &self.saved,
+            &self.cache,
+            &self.device,
+        )?;
+        if self.format == Format::Sqlite {
+            self.saved = self.cache.clone();
+        }
+        Ok(())
+    }
+
+    /// Appends a change of each line at that time to the event log, numbered after the last
+    /// event, saves being logged with their context
+    fn append(
+        &mut self,
+        changes: Vec<(Vec<u8>, Change)>,
+        saved_value: &SavedValue,
+        time: i64,
+    ) -> Result<()> {
+        let events: Vec<Event> = changes
+            .into_iter()
+            .zip(self.cache.last_event + 1..)
+            .map(|((key, change), seq)| Event {
+                seq,
+                time,
+                mode: saved_value.clone(),
+                key,
+                context: match change {
+                    Change::Save => self.context.clone(),
+                    _ => None,
+                },
+                change,
+            })
+            .collect();
+        self.cache.last_event += events.len() as u64;
+        append_events(&self.log_path, &events)?;
+        self.events.extend(events);
+        Ok(())
+    }
+
+    /// Whether the log holds too many events, its oldest half then being dropped
+    fn log_full(&self) -> bool {
+        self.events.len() > self.limit.max_log_events
+    }
+
+    /// Writes the cache file, dropping the oldest half of the log once it holds too many events
+    fn write_logged(&mut self) -> Result<()> {
+        if self.log_full() {
+            self.trim_log(self.limit.max_log_events / 2)
+        } else {
+            self.write()
+        }
+    }
+
+    /// Logs that the entries of these lines were forgotten, then writes the cache file
+    fn log_removed(&mut self, keys: Vec<Vec<u8>>) -> Result<()> {
+        let saved_value = self.cache.mode.clone().unwrap_or_default();
+        let changes = keys.into_iter().map(|key| (key, Change::Remove)).collect();
+        self.append(changes, &saved_value, now())?;
+        self.write_logged()
+    }
+
+    /// Drops the oldest events of the log to keep at most that many,
+    /// once the cache file holds them so that a run killed halfway loses none.
+    /// The log is only ever rewritten here, every other change is appended to it
+    fn trim_log(&mut self, kept: usize) -> Result<()> {
+        if self.events.len() <= kept {
+            return self.write();
+        }
+        let dropped = self.events.len() - kept;
+        self.cache.trimmed = self.events[dropped - 1].seq;
+        self.events.drain(..dropped);
+        self.write()?;
+        write_events(&self.log_path, &self.events)
+    }
+
+    /// Folds the event log into the cache file and empties it
+    pub fn compact(&mut self) -> Result<()> {
+        self.trim_log(0)
+    }
+
+    /// Rewrites the cache in another format
+    pub fn convert(&mut self, format: Format) -> Result<()> {
+        if format == self.format {
+            return Ok(());
+        }
+        convert_cache(&self.path, &self.cache, format, &self.device)?;
+        self.format = format;
+        if format == Format::Lines {
+            self.cache.devices = devices_of(&self.cache, &self.device);
+        }
+        // the log follows, its sequence numbers being those of the saves of this device
+        let log_path = log_path(&self.path, format, &self.device);
+        if self.log_path.exists() {
+            std::fs::rename(&self.log_path, &log_path)?;
+        }
+        self.log_path = log_path;
+        self.saved = match format {
+            Format::Json | Format::Lines => Cache::default(),
+            Format::Sqlite => self.cache.clone(),
+        };
+        Ok(())
+    }
+
+    /// Refuses to save in another mode than the cache was first saved with
+    fn check_mode(&mut self, saved_value: &SavedValue) -> Result<()> {
+        match &self.cache.mode {
+            Some(mode) if mode != saved_value => Err(Error::ModeMismatch {
+                path: self.path.clone(),
+                mode: mode.clone(),
+                requested: saved_value.clone(),
+            }),
+            Some(_) => Ok(()),
+            None => {
+                self.cache.mode = Some(saved_value.clone());
+                Ok(())
+            }
+        }
+    }
+
+    /// Refuses to scale or overwrite counts in lines caches, where the copies of a device
+    /// are merged by keeping the highest
+    fn check_counts_grow(&self, action: &str) -> Result<()> {
+        if self.format != Format::Lines {
+            return Ok(());
+        }
+        Err(Error::CountsPerDevice {
+            path: self.path.clone(),
+            action: action.to_string(),
+        })
+    }
+
+    /// Orders the lines by their value, then puts pinned lines at the top or bottom
+    /// and hides blocked ones or leaves them unranked
+    pub fn sort(&mut self, lines: Vec<Vec<u8>>, options: &SortOptions) -> Result<Vec<Vec<u8>>> {
+        let mut lines = get_asc_sorted_lines(
+            &options.value,
+            lines,
+            &self.cache.entries,
+            &self.key,
+            self.aging.half_life,
+        )?;
+        if let Blocked::Hide = options.blocked {
+            lines.retain(|line| !self.cache.blocked.contains(self.key.key(line).as_ref()));
+        }
+        if options.desc {
+            lines = lines.into_iter().rev().collect();
+        }
+        if options.cleanup {
+            let before: Vec<Vec<u8>> = self.cache.entries.keys().cloned().collect();
+            cleanup(&mut self.cache.entries, &lines, &self.key);
+            let blocked = &self.cache.blocked;
+            self.cache.entries.retain(|key, _| !blocked.contains(key));
+            let mut removed: Vec<Vec<u8>> = before
+                .into_iter()
+                .filter(|key| !self.cache.entries.contains_key(key))
+                .collect();
+            removed.sort();
+            evict(&mut self.cache.entries, &self.limit, &[]);
+            self.log_removed(removed)?;
+        }
+        Ok(place_pinned(lines, &self.cache.pinned, &self.key))
+    }
+
+    /// Saves that every line of the selection but the blocked ones was used, returning all
+    /// of them to be printed back, as only sorting hides blocked lines
+    pub fn record(
+        &mut self,
+        selection: &[Vec<u8>],
+        saved_value: &SavedValue,
+    ) -> Result<Vec<Vec<u8>>> {
+        if self.aging.max_total.is_some() {
+            self.check_counts_grow("aging with max_total")?;
+        }
+        self.check_mode(saved_value)?;
+        let unblocked: Vec<Vec<u8>> = selection
+            .iter()
+            .filter(|line| !self.cache.blocked.contains(self.key.key(line).as_ref()))
+            .cloned()
+            .collect();
+        let now = now();
+        update_stdin_lines(
+            saved_value,
+            &unblocked,
+            &mut self.cache.entries,
+            &self.key,
+            now,
+        );
+        let saved: Vec<Vec<u8>> = unblocked
+            .iter()
+            .map(|line| self.key.key(line).into_owned())
+            .collect();
+        // appending is all a save writes, unless the cache file has to change for other reasons
+        // or is a lines cache, whose rows of this device are rewritten for other devices to see
+        let changes = saved
+            .iter()
+            .map(|key| (key.clone(), Change::Save))
+            .collect();
+        self.append(changes, saved_value, now)?;
+        let aged = age(&mut self.cache.entries, &self.aging);
+        let evicted = evict(&mut self.cache.entries, &self.limit, &saved);
+        if aged || evicted || self.log_full() || self.format == Format::Lines {
+            self.write_logged()?;
+        }
+        Ok(selection.to_vec())
+    }
+
+    /// Records the first `max_lines` lines and returns all of them, to be printed back whole
+    /// for whatever reads the selection next
+    pub fn save(
+        &mut self,
+        lines: Vec<Vec<u8>>,
+        max_lines: usize,
+        saved_value: &SavedValue,
+    ) -> Result<Vec<Vec<u8>>> {
+        self.record(&lines[..lines.len().min(max_lines)], saved_value)?;
+        Ok(lines)
+    }
+
+    /// Sorts the lines, lets the user choose among them with the picker and records
+    /// the first `max_lines` chosen, all while the cache is loaded once.
+    /// Lines are passed to and read from the picker ended by the delimiter.
+    /// Returns every chosen line and the exit code of the picker, nothing is saved if it failed
+    pub fn pick(
+        &mut self,
+        lines: Vec<Vec<u8>>,
+        options: &SortOptions,
+        max_lines: usize,
+        picker: &str,
+        delimiter: u8,
+    ) -> Result<(Vec<Vec<u8>>, i32)> {
+        let lines = self.sort(lines, options)?;
+        let (selection, exit_code) = run_picker(picker, &lines, delimiter)?;
+        if exit_code != 0 {
+            return Ok((Vec::new(), exit_code));
+        }
+        Ok((self.save(selection, max_lines, &options.value)?, exit_code))
+    }
+
+    /// Prefixes each line with its value, to see why it is ranked where it is
+    pub fn explain(&self, lines: Vec<Vec<u8>>, saved_value: &SavedValue) -> Vec<Vec<u8>> {
+        let now = now();
+        lines
+            .into_iter()
+            .map(|line| {
+                let value = get_value(
+                    saved_value,
+                    &self.cache.entries,
+                    &self.key.key(&line),
+                    now,
+                    self.aging.half_life,
+                );
+                tab_separated(&[value.to_string().as_bytes(), &line])
+            })
+            .collect()
+    }
+
+    /// Prints all the entries of the cache, ordered by value
+    pub fn list(&self, saved_value: &SavedValue, desc: bool, format: &ListFormat) -> Vec<Vec<u8>> {
+        let now = now();
+        let mut entries: Vec<(&Vec<u8>, &Entry, f64)> = self
+            .cache
+            .entries
+            .iter()
+            .map(|(key, entry)| (key, entry, score(saved_value, entry, now)))
+            .collect();
+        entries.sort_by(|a, b| a.2.total_cmp(&b.2).then_with(|| a.0.cmp(b.0)));
+        if desc {
+            entries.reverse();
+        }
+        match format {
+            ListFormat::Plain => entries
+                .iter()
+                .map(|(key, _, value)| tab_separated(&[value.to_string().as_bytes(), key]))
+                .collect(),
+            ListFormat::Json => {
+                let entries: Vec<ListedEntry> = entries
+                    .iter()
+                    .map(|(key, entry, value)| ListedEntry {
+                        key: encode_key(key),
+                        value: *value,
+                        count: entry.count,
+                        first_seen: entry.first_seen,
+                        last_used: entry.last_used,
+                        score: entry.score,
+                    })
+                    .collect();
+                Vec::from([json::to_string(&entries).into_bytes()])
+            }
+            ListFormat::Tsv => {
+                std::iter::once(b"key\tvalue\tcount\tfirst_seen\tlast_used\tscore".to_vec())
+                    .chain(entries.iter().map(|(key, entry, value)| {
+                        tab_separated(&[
+                            key,
+                            value.to_string().as_bytes(),
+                            entry.count.to_string().as_bytes(),
+                            entry.first_seen.to_string().as_bytes(),
+                            entry.last_used.to_string().as_bytes(),
+                            entry.score.to_string().as_bytes(),
+                        ])
+                    }))
+                    .collect()
+            }
+        }
+    }
+
+    /// Prints every field saved for a line
+    pub fn show(&self, key: &[u8], saved_value: &SavedValue) -> Result<Vec<Vec<u8>>> {
+        let entry = self
+            .cache
+            .entries
+            .get(key)
+            .ok_or_else(|| Error::NoEntry(key.to_vec()))?;
+        Ok(Vec::from([
+            [&b"key: "[..], key].concat(),
+            format!("value: {}", score(saved_value, entry, now())).into_bytes(),
+            format!("count: {}", entry.count).into_bytes(),
+            format!("first_seen: {}", entry.first_seen).into_bytes(),
+            format!("last_used: {}", entry.last_used).into_bytes(),
+            format!("score: {}", entry.score).into_bytes(),
+        ]))
+    }
+
+    /// Forgets the given lines and the ones matching the glob, returning what was removed
+    pub fn remove(&mut self, keys: &[Vec<u8>], pattern: Option<&[u8]>) -> Result<Vec<Vec<u8>>> {
+        let mut removed: Vec<Vec<u8>> = self
+            .cache
+            .entries
+            .keys()
+            .filter(|key| keys.contains(key) || pattern.is_some_and(|p| glob_match(p, key)))
+            .cloned()
+            .collect();
+        removed.sort();
+        for key in &removed {
+            self.cache.entries.remove(key);
+        }
+        self.log_removed(removed.clone())?;
+        Ok(removed)
+    }
+
+    /// Forgets every entry of the cache and its mode, pinned and blocked keys are kept
+    pub fn reset(&mut self) -> Result<()> {
+        let mut removed: Vec<Vec<u8>> = self.cache.entries.drain().map(|(key, _)| key).collect();
+        removed.sort();
+        self.cache.mode = None;
+        self.cache.unlogged = false;
+        self.log_removed(removed)
+    }
+
+    /// Moves the entry of a line to another one, adding up with the entry the other line may have
+    pub fn rename(&mut self, from: &[u8], to: &[u8]) -> Result<()> {
+        if !rename_entry(&mut self.cache.entries, from, to) {
+            return Err(Error::NoEntry(from.to_vec()));
+        }
+        let saved_value = self.cache.mode.clone().unwrap_or_default();
+        self.append(
+            Vec::from([(from.to_vec(), Change::Rename(to.to_vec()))]),
+            &saved_value,
+            now(),
+        )?;
+        self.write_logged()
+    }
+
+    /// Puts the lines of these keys at the top or bottom of sorted lines
+    pub fn pin(&mut self, keys: &[Vec<u8>], pin: Pin) -> Result<()> {
+        for key in keys {
+            self.cache.pinned.insert(key.clone(), pin);
+        }
+        self.write()
+    }
+
+    pub fn unpin(&mut self, keys: &[Vec<u8>]) -> Result<()> {
+        for key in keys {
+            self.cache.pinned.remove(key);
+        }
+        self.write()
+    }
+
+    /// Forgets these keys and never saves them again until they are unblocked
+    pub fn block(&mut self, keys: &[Vec<u8>]) -> Result<()> {
+        let mut removed = Vec::new();
+        for key in keys {
+            if self.cache.entries.remove(key).is_some() {
+                removed.push(key.clone());
+            }
+            self.cache.pinned.remove(key);
+            self.cache.blocked.insert(key.clone());
+        }
+        self.log_removed(removed)
+    }
+
+    pub fn unblock(&mut self, keys: &[Vec<u8>]) -> Result<()> {
+        for key in keys {
+            self.cache.blocked.remove(key);
+        }
+        self.write()
+    }
+
+    /// Prints the pinned keys after where they are pinned
+    pub fn pins(&self) -> Vec<Vec<u8>> {
+        let mut pins: Vec<Vec<u8>> = self
+            .cache
+            .pinned
+            .iter()
+            .map(|(key, pin)| {
+                let pin = match pin {
+                    Pin::Top => &b"top"[..],
+                    Pin::Bottom => b"bottom",
+                };
+                tab_separated(&[pin, key])
+            })
+            .collect();
+        pins.sort();
+        pins
+    }
+
+    /// Prints the blocked keys
+    pub fn blocked(&self) -> Vec<Vec<u8>> {
+        let mut blocked: Vec<Vec<u8>> = self.cache.blocked.iter().cloned().collect();
+        blocked.sort();
+        blocked
+    }
+
+    /// Prints every entry, ordered by line, in a format other tools or another baus can import
+    pub fn export(&self, format: ExchangeFormat) -> Vec<u8> {
+        export(&self.cache.entries, format)
+    }
+
+    /// Adds the entries read from the file in that format, combined by the merge strategy with
+    /// the ones of the same lines, blocked lines being left out. Imported entries are not logged
+    pub fn import(
+        &mut self,
+        path: &Path,
+        input: &[u8],
+        format: ExchangeFormat,
+        merge: Merge,
+    ) -> Result<()> {
+        let saved_value = self.cache.mode.clone().unwrap_or_default();
+        let entries = import(path, input, format, &saved_value)?;
+        self.merge_entries(entries, merge, &saved_value)
+    }
+
+    /// Seeds the cache with the history of another tool, mapping its ranks or the number of
+    /// times commands appear to counts, combined by the merge strategy as with `import`
+    pub fn import_history(
+        &mut self,
+        path: &Path,
+        input: &[u8],
+        history: History,
+        merge: Merge,
+    ) -> Result<()> {
+        let saved_value = self.cache.mode.clone().unwrap_or_default();
+        let entries = import_history(path, input, history, &saved_value, now())?;
+        self.merge_entries(entries, merge, &saved_value)
+    }
+
+    fn merge_entries(
+        &mut self,
+        entries: Vec<(Vec<u8>, Entry)>,
+        merge: Merge,
+        saved_value: &SavedValue,
+    ) -> Result<()> {
+        if merge != Merge::Sum {
+            self.check_counts_grow("importing without summing")?;
+        }
+        self.cache.unlogged = true;
+        for (key, entry) in entries {
+            if !self.cache.blocked.contains(&key) {
+                merge_entry(&mut self.cache.entries, key, entry, merge, saved_value);
+            }
+        }
+        evict(&mut self.cache.entries, &self.limit, &[]);
+        self.write()
+    }
+
+    /// Merges other copies of a lines cache in, such as the one of another machine or one a sync
+    /// left conflicting, device by device: counts of the same device keep the highest, last
+    /// uses the latest, then the devices are added up. Copies without devices count as one
+    /// unnamed device. Pins and blocked lines are merged too, the mode kept when set
+    pub fn merge(&mut self, paths: &[PathBuf]) -> Result<()> {
+        if self.format != Format::Lines {
+            return Err(Error::MergeNotLines(self.path.clone()));
+        }
+        let mut devices = devices_of(&self.cache, &self.device);
+        for path in paths {
+            if !path.is_file() {
+                return Err(Error::NoCacheFile(path.clone()));
+            }
+            let (other, set_aside) = load_snapshot(path, &self.device)?;
+            self.set_aside.extend(set_aside);
+            if other.devices.is_empty() {
+                merge_devices(&mut devices, &devices_of(&other, ""));
+            } else {
+                merge_devices(&mut devices, &other.devices);
+            }
+            for (key, pin) in other.pinned {
+                self.cache.pinned.entry(key).or_insert(pin);
+            }
+            self.cache.blocked.extend(other.blocked);
+            if self.cache.mode.is_none() {
+                self.cache.mode = other.mode;
+            }
+        }
+        let saved_value = self.cache.mode.clone().unwrap_or_default();
+        self.cache.entries = total_entries(&devices, &saved_value);
+        let blocked = &self.cache.blocked;
+        self.cache.entries.retain(|key, _| !blocked.contains(key));
+        self.cache.devices = devices;
+        self.cache.unlogged = true;
+        evict(&mut self.cache.entries, &self.limit, &[]);
+        self.write()
+    }
+
+    /// Prints the events of the log, oldest first, with their time: saves with their value
+    /// and context, removals and renames with the line renamed to
+    pub fn log(&self) -> Vec<Vec<u8>> {
+        self.events
+            .iter()
+            .map(|event| {
+                let time = event.time.to_string();
+                match &event.change {
+                    Change::Save => tab_separated(&[
+                        time.as_bytes(),
+                        event.mode.to_string().as_bytes(),
+                        &event.key,
+                        event.context.as_deref().unwrap_or_default().as_bytes(),
+                    ]),
+                    Change::Remove => tab_separated(&[time.as_bytes(), b"remove", &event.key]),
+                    Change::Rename(to) => {
+                        tab_separated(&[time.as_bytes(), b"rename", &event.key, to])
+                    }
+                }
+            })
+            .collect()
+    }
+
+    /// Recomputes the entries from the events of the log with that value, then ages
+    /// and evicts them. It is refused once saves were dropped from the log or changes it does
+    /// not hold, such as imports, merges or counts set by hand, were made since the cache was
+    /// reset, and for lines caches, where the log only holds the saves of this device
+    pub fn replay(&mut self, saved_value: &SavedValue) -> Result<()> {
+        self.check_counts_grow("replaying")?;
+        if self.cache.unlogged {
+            return Err(Error::Unlogged(self.path.clone()));
+        }
+        if self.cache.trimmed > 0 {
+            return Err(Error::LogTrimmed {
+                path: self.log_path.clone(),
+                trimmed: self.cache.trimmed,
+            });
+        }
+        self.cache.entries.clear();
+        self.cache.mode = Some(saved_value.clone());
+        for event in &self.events {
+            change_entries(&mut self.cache, event, saved_value);
+        }
+        age(&mut self.cache.entries, &self.aging);
+        evict(&mut self.cache.entries, &self.limit, &[]);
+        self.write()
+    }
+
+    /// Sets the count or last use of a line, depending on the value, which is not logged.
+    /// Lines caches are refused, their counts only ever growing
+    pub fn set(&mut self, key: &[u8], value: i64, saved_value: &SavedValue) -> Result<()> {
+        self.check_counts_grow("setting counts")?;
+        self.check_mode(saved_value)?;
+        self.cache.unlogged = true;
+        let now = now();
+        let entry = self
+            .cache
+            .entries
+            .entry(key.to_vec())
+            .or_insert_with(|| Entry {
+                first_seen: now,
+                ..Entry::new()
+            });
+        match saved_value {
+            SavedValue::Count => entry.count = value,
+            SavedValue::Timestamp => entry.last_used = value,
+            SavedValue::Frecency => {
+                entry.count = value;
+                entry.last_used = now;
+            }
+        }
+        entry.score = score(saved_value, entry, now);
+        self.write()
+    }
+}
+
+#[cfg(test)]
+use crate::cache::test_dir;
+#[cfg(test)]
+use crate::cache::{get_cache, get_lines_backup};
+#[cfg(test)]
+use crate::{entry, lines, KeyExtractor, Normalizer, NEWLINE};
+#[cfg(test)]
+use std::collections::HashSet;
+
+#[cfg(test)]
+fn test_store(name: &str, lines_backup: LinesBackup) -> (PathBuf, Store) {
+    let dir = test_dir(name);
+    let mut store = Store::open_path(dir.join("animals")).unwrap();
+    store.cache.entries = lines_backup;
+    store.write().unwrap();
+    (dir, store)
+}
+
+#[test]
+fn test_update_first_stdin_line() {
+    let (dir, mut store) = test_store(
+        "first",
+        HashMap::from([
+            (b"horse".to_vec(), entry(2, 0)),
+            (b"hamster".to_vec(), entry(1, 0)),
+        ]),
+    );
+    let saved = store
+        .save(lines(&["horse", "hamster"]), 1, &SavedValue::Count)
+        .unwrap();
+    let none = store
+        .save(lines(&["horse", "hamster"]), 0, &SavedValue::Count)
+        .unwrap();
+    let cache = get_lines_backup(store.path()).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(saved, lines(&["horse", "hamster"]));
+    assert_eq!(none, lines(&["horse", "hamster"]));
+    assert_eq!(cache.get(&b"horse"[..]).unwrap().count, 3);
+    assert!(cache.get(&b"horse"[..]).unwrap().last_used > 0);
+    assert_eq!(cache.get(&b"horse"[..]).unwrap().score, 3.0);
+    assert_eq!(cache.get(&b"hamster"[..]), Some(&entry(1, 0)))
+}
+
+#[test]
+fn test_update_all_stdin_lines() {
+    let mut cache = HashMap::from([(b"horse".to_vec(), entry(2, 0))]);
+    assert_eq!(
+        update_stdin_lines(
+            &SavedValue::Count,
+            &lines(&["horse", "hamster", "cat"]),
+            &mut cache,
+            &KeyOptions::default(),
+            now(),
+        ),
+        lines(&["horse", "hamster", "cat"])
+    );
+    assert_eq!(cache.get(&b"horse"[..]).unwrap().count, 3);
+    assert_eq!(cache.get(&b"hamster"[..]).unwrap().count, 1);
+    assert_eq!(cache.get(&b"cat"[..]).unwrap().count, 1)
+}
+
+#[test]
+fn test_concurrent_saves() {
+    let dir = test_dir("concurrent");
+    let cache_file_path = dir.join("animals");
+    std::thread::scope(|scope| {
+        for _ in 0..50 {
+            scope.spawn(|| {
+                Store::open_path(&cache_file_path)
+                    .unwrap()
+                    .record(&lines(&["horse"]), &SavedValue::Count)
+                    .unwrap();
+            });
+        }
+    });
+    let lines_backup = get_lines_backup(&cache_file_path).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(lines_backup.get(&b"horse"[..]).unwrap().count, 50);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_pick() {
+    let (dir, mut store) = test_store("pick", HashMap::from([(b"horse".to_vec(), entry(2, 0))]));
+    let options = SortOptions {
+        desc: true,
+        ..SortOptions::default()
+    };
+    let picked = store
+        .pick(
+            lines(&["horse", "hamster"]),
+            &options,
+            1,
+            "tail -n 1",
+            NEWLINE,
+        )
+        .unwrap();
+    let saved = get_lines_backup(store.path()).unwrap();
+    let cancelled = store
+        .pick(
+            lines(&["horse", "hamster"]),
+            &options,
+            1,
+            "exit 130",
+            NEWLINE,
+        )
+        .unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(picked, (lines(&["hamster"]), 0));
+    assert_eq!(saved.get(&b"hamster"[..]).unwrap().count, 1);
+    assert_eq!(cancelled, (Vec::new(), 130));
+}
+
+#[test]
+fn test_explain() {
+    let (dir, store) = test_store("explain", HashMap::from([(b"horse".to_vec(), entry(2, 0))]));
+    let explained = store.explain(lines(&["hamster", "horse"]), &SavedValue::Count);
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(explained, lines(&["0\thamster", "2\thorse"]))
+}
+
+#[test]
+fn test_key_extraction_keeps_lines() {
+    let (dir, store) = test_store("key", HashMap::from([(b"horse".to_vec(), entry(2, 0))]));
+    let mut store = store.with_key(KeyOptions {
+        extractor: KeyExtractor::capture(":(\\w+)$", None).unwrap(),
+        normalizers: Vec::from([Normalizer::IgnoreCase]),
+    });
+    let recorded = store
+        .record(&lines(&["1:hamster", "2:Horse"]), &SavedValue::Count)
+        .unwrap();
+    let sorted = store
+        .sort(lines(&["9:horse", "8:cat"]), &SortOptions::default())
+        .unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(recorded, lines(&["1:hamster", "2:Horse"]));
+    assert_eq!(store.entries().get(&b"horse"[..]).unwrap().count, 3);
+    assert_eq!(store.entries().get(&b"hamster"[..]).unwrap().count, 1);
+    assert_eq!(sorted, lines(&["8:cat", "9:horse"]));
+}
+
+#[test]
+fn test_aging() {
+    let (dir, store) = test_store(
+        "aging",
+        HashMap::from([
+            (b"horse".to_vec(), entry(80, 0)),
+            (b"hamster".to_vec(), entry(1, 0)),
+        ]),
+    );
+    let mut store = store.with_aging(AgingOptions {
+        max_total: Some(100),
+        ..AgingOptions::default()
+    });
+    store.record(&lines(&["cat"]), &SavedValue::Count).unwrap();
+    let untouched = store.entries().len();
+    store.set(b"cat", 19, &SavedValue::Count).unwrap();
+    store.record(&lines(&["cat"]), &SavedValue::Count).unwrap();
+    let saved = get_lines_backup(store.path()).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(untouched, 3);
+    assert_eq!(saved.len(), 2);
+    assert_eq!(saved.get(&b"horse"[..]).unwrap().count, 71);
+    assert_eq!(saved.get(&b"cat"[..]).unwrap().count, 17);
+}
+
+#[test]
+fn test_evict() {
+    let lines_backup = HashMap::from([
+        (
+            b"horse".to_vec(),
+            Entry {
+                first_seen: 1,
+                last_used: 30,
+                count: 1,
+                score: 5.0,
+            },
+        ),
+        (
+            b"hamster".to_vec(),
+            Entry {
+                first_seen: 2,
+                last_used: 10,
+                count: 1,
+                score: 3.0,
+            },
+        ),
+        (
+            b"cat".to_vec(),
+            Entry {
+                first_seen: 3,
+                last_used: 20,
+                count: 1,
+                score: 1.0,
+            },
+        ),
+    ]);
+    let evicted = |eviction, kept: &[Vec<u8>]| {
+        let mut lines_backup = lines_backup.clone();
+        let limit = LimitOptions {
+            max_entries: Some(2),
+            eviction,
+            ..LimitOptions::default()
+        };
+        evict(&mut lines_backup, &limit, kept);
+        let mut keys: Vec<Vec<u8>> = lines_backup.into_keys().collect();
+        keys.sort();
+        keys
+    };
+    assert_eq!(
+        evicted(Eviction::LowestScore, &[]),
+        lines(&["hamster", "horse"])
+    );
+    assert_eq!(
+        evicted(Eviction::LowestScore, &lines(&["cat"])),
+        lines(&["cat", "horse"])
+    );
+    assert_eq!(
+        evicted(Eviction::LeastRecentlyUsed, &[]),
+        lines(&["cat", "horse"])
+    );
+    assert_eq!(evicted(Eviction::Oldest, &[]), lines(&["cat", "hamster"]));
+}
+
+#[test]
+fn test_pinned_and_blocked() {
+    let (dir, store) = test_store(
+        "pinned",
+        HashMap::from([
+            (b"horse".to_vec(), entry(3, 0)),
+            (b"hamster".to_vec(), entry(2, 0)),
+            (b"cat".to_vec(), entry(1, 0)),
+        ]),
+    );
+    let mut store = store.with_key(KeyOptions {
+        normalizers: Vec::from([Normalizer::IgnoreCase]),
+        ..KeyOptions::default()
+    });
+    store.pin(&lines(&["cat"]), Pin::Top).unwrap();
+    store.pin(&lines(&["horse"]), Pin::Bottom).unwrap();
+    store.block(&lines(&["hamster", "dog"])).unwrap();
+    let recorded = store
+        .record(&lines(&["dog", "cow"]), &SavedValue::Count)
+        .unwrap();
+    let all = lines(&["Horse", "hamster", "cat", "dog", "cow"]);
+    let options = SortOptions {
+        desc: true,
+        ..SortOptions::default()
+    };
+    let hidden = store.sort(all.clone(), &options).unwrap();
+    let options = SortOptions {
+        blocked: Blocked::Unranked,
+        ..options
+    };
+    let unranked = store.sort(all, &options).unwrap();
+    let pins = store.pins();
+    store.unblock(&lines(&["dog"])).unwrap();
+    store.unpin(&lines(&["cat"])).unwrap();
+    let saved = get_cache(store.path()).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(recorded, lines(&["dog", "cow"]));
+    assert!(!saved.entries.contains_key(&b"dog"[..]));
+    assert_eq!(saved.entries.get(&b"cow"[..]).unwrap().count, 1);
+    assert_eq!(hidden, lines(&["cat", "cow", "Horse"]));
+    assert_eq!(unranked, lines(&["cat", "cow", "dog", "hamster", "Horse"]));
+    assert_eq!(pins, lines(&["bottom\thorse", "top\tcat"]));
+    assert!(!saved.entries.contains_key(&b"hamster"[..]));
+    assert_eq!(saved.blocked, HashSet::from([b"hamster".to_vec()]));
+    assert_eq!(
+        saved.pinned,
+        HashMap::from([(b"horse".to_vec(), Pin::Bottom)])
+    );
+}
+
+#[test]
+fn test_mode_mismatch() {
+    let (dir, mut store) = test_store("mode", HashMap::new());
+    store
+        .record(&lines(&["horse"]), &SavedValue::Timestamp)
+        .unwrap();
+    let count = store.record(&lines(&["horse"]), &SavedValue::Count);
+    let set = store.set(b"horse", 1, &SavedValue::Frecency);
+    store.reset().unwrap();
+    let reset = store.record(&lines(&["horse"]), &SavedValue::Count);
+    let saved = get_cache(store.path()).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert!(matches!(
+        count,
+        Err(Error::ModeMismatch {
+            mode: SavedValue::Timestamp,
+            requested: SavedValue::Count,
+            ..
+        })
+    ));
+    assert!(set.is_err());
+    assert!(reset.is_ok());
+    assert_eq!(saved.mode, Some(SavedValue::Count));
+}
+
+#[test]
+fn test_event_log() {
+    let (dir, store) = test_store("events", HashMap::new());
+    let mut store = store
+        .with_limit(LimitOptions {
+            max_log_events: 4,
+            ..LimitOptions::default()
+        })
+        .with_context(Some("/home".to_string()));
+    let snapshot = std::fs::read(store.path()).unwrap();
+    store
+        .record(&lines(&["horse", "cat"]), &SavedValue::Count)
+        .unwrap();
+    store
+        .record(&lines(&["horse"]), &SavedValue::Count)
+        .unwrap();
+    let appended = std::fs::read(store.path()).unwrap() == snapshot;
+    let logged = get_cache(store.path()).unwrap();
+    let log = store.log();
+    store.replay(&SavedValue::Timestamp).unwrap();
+    let replayed = get_cache(store.path()).unwrap();
+    store
+        .record(&lines(&["cat", "cow"]), &SavedValue::Timestamp)
+        .unwrap();
+    let kept: Vec<u64> = store.events().iter().map(|event| event.seq).collect();
+    let replay = store.replay(&SavedValue::Count);
+    store.compact().unwrap();
+    let compacted = get_cache(store.path()).unwrap();
+    let log_removed = !log_path(store.path(), store.format(), "").exists();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert!(appended);
+    assert_eq!(logged.last_event, 3);
+    assert_eq!(logged.entries.get(&b"horse"[..]).unwrap().count, 2);
+    assert_eq!(logged.entries.get(&b"cat"[..]).unwrap().count, 1);
+    assert_eq!(log.len(), 3);
+    assert!(log[2].ends_with(b"\tcount\thorse\t/home"));
+    assert_eq!(replayed.mode, Some(SavedValue::Timestamp));
+    let horse = replayed.entries.get(&b"horse"[..]).unwrap();
+    assert_eq!(horse.score, horse.last_used as f64);
+    assert_eq!(kept, Vec::from([4, 5]));
+    assert!(matches!(replay, Err(Error::LogTrimmed { trimmed: 3, .. })));
+    assert_eq!(&compacted, store.cache());
+    assert!(log_removed);
+}
+
+#[test]
+fn test_log_removals() {
+    let (dir, mut store) = test_store("removals", HashMap::new());
+    store
+        .record(&lines(&["horse", "cat", "cow"]), &SavedValue::Count)
+        .unwrap();
+    let logged = std::fs::read(log_path(store.path(), store.format(), "")).unwrap();
+    let options = SortOptions {
+        cleanup: true,
+        ..SortOptions::default()
+    };
+    store.sort(lines(&["horse", "cat"]), &options).unwrap();
+    store.rename(b"cat", b"dog").unwrap();
+    store.record(&lines(&["cow"]), &SavedValue::Count).unwrap();
+    let appended = std::fs::read(log_path(store.path(), store.format(), ""))
+        .unwrap()
+        .starts_with(&logged);
+    let log = store.log();
+    store.replay(&SavedValue::Count).unwrap();
+    let replayed = get_lines_backup(store.path()).unwrap();
+    store
+        .import(
+            Path::new("-"),
+            b"key,count\nhorse,40\n",
+            ExchangeFormat::Csv,
+            Merge::Sum,
+        )
+        .unwrap();
+    let imported = store.replay(&SavedValue::Count);
+    store.reset().unwrap();
+    store.record(&lines(&["cat"]), &SavedValue::Count).unwrap();
+    let reset = store.replay(&SavedValue::Count);
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert!(appended);
+    assert!(matches!(imported, Err(Error::Unlogged(_))));
+    assert!(reset.is_ok());
+    assert_eq!(log.len(), 6);
+    assert!(log[3].ends_with(b"\tremove\tcow"));
+    assert!(log[4].ends_with(b"\trename\tcat\tdog"));
+    let counts: HashMap<Vec<u8>, i64> = replayed
+        .into_iter()
+        .map(|(key, entry)| (key, entry.count))
+        .collect();
+    assert_eq!(
+        counts,
+        HashMap::from([
+            (b"horse".to_vec(), 1),
+            (b"dog".to_vec(), 1),
+            (b"cow".to_vec(), 1)
+        ])
+    );
+}
+
+#[test]
+fn test_device_logs() {
+    let dir = test_dir("devices");
+    let cache_file_path = dir.join("animals");
+    let mut store = Store::open_path_as(&cache_file_path, "laptop").unwrap();
+    store.record(&lines(&["x"]), &SavedValue::Count).unwrap();
+    store.convert(Format::Lines).unwrap();
+    store.record(&lines(&["x"]), &SavedValue::Count).unwrap();
+    drop(store);
+    let mut desktop = Store::open_path_as(&cache_file_path, "desktop").unwrap();
+    let count = desktop.entries().get(&b"x"[..]).unwrap().count;
+    desktop.compact().unwrap();
+    drop(desktop);
+    let mut laptop = Store::open_path_as(&cache_file_path, "laptop").unwrap();
+    laptop.compact().unwrap();
+    let set = laptop.set(b"x", 5, &SavedValue::Count);
+    let replay = laptop.replay(&SavedValue::Count);
+    let mut laptop = laptop.with_aging(AgingOptions {
+        max_total: Some(1),
+        ..AgingOptions::default()
+    });
+    let aged = laptop.record(&lines(&["x"]), &SavedValue::Count);
+    let overwritten = laptop.import(
+        Path::new("-"),
+        b"key,count\nx,1\n",
+        ExchangeFormat::Csv,
+        Merge::Overwrite,
+    );
+    drop(laptop);
+    let compacted = std::fs::read(&cache_file_path).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(count, 2);
+    for refused in [set, replay, overwritten] {
+        assert!(matches!(refused, Err(Error::CountsPerDevice { .. })));
+    }
+    assert!(matches!(aged, Err(Error::CountsPerDevice { .. })));
+    let rows: Vec<&[u8]> = compacted
+        .split(|b| *b == b'\n')
+        .filter(|row| row.starts_with(b"entry\t"))
+        .collect();
+    assert_eq!(rows.len(), 1);
+    assert!(rows[0].starts_with(b"entry\tlaptop\tx\t2\t"));
+}
+
+#[test]
+fn test_lines_removals() {
+    let dir = test_dir("tombstones");
+    let cache_file_path = dir.join("animals");
+    let older = dir.join("animals.sync-conflict");
+    let mut store = Store::open_path_as(&cache_file_path, "laptop").unwrap();
+    store.convert(Format::Lines).unwrap();
+    store
+        .record(&lines(&["x", "y", "y", "y"]), &SavedValue::Count)
+        .unwrap();
+    std::fs::copy(&cache_file_path, &older).unwrap();
+    store.remove(&lines(&["y"]), None).unwrap();
+    store.merge(&[older]).unwrap();
+    let merged = store.entries().clone();
+    drop(store);
+    let mut json = Store::open_path(dir.join("json")).unwrap();
+    let refused = json.merge(std::slice::from_ref(&cache_file_path));
+    drop(json);
+    let desktop = Store::open_path_as(&cache_file_path, "desktop").unwrap();
+    let seen = desktop.entries().clone();
+    drop(desktop);
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert!(matches!(refused, Err(Error::MergeNotLines(_))));
+    for entries in [merged, seen] {
+        assert_eq!(entries.get(&b"x"[..]).unwrap().count, 1);
+        assert!(!entries.contains_key(&b"y"[..]));
+    }
+}
+
+#[test]
+fn test_list() {
+    let (dir, store) = test_store(
+        "list",
+        HashMap::from([
+            (b"horse".to_vec(), entry(2, 10)),
+            (b"hamster".to_vec(), entry(1, 20)),
+        ]),
+    );
+    let plain = store.list(&SavedValue::Count, true, &ListFormat::Plain);
+    let tsv = store.list(&SavedValue::Timestamp, false, &ListFormat::Tsv);
+    let json = store.list(&SavedValue::Count, false, &ListFormat::Json);
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(plain, lines(&["2\thorse", "1\thamster"]));
+    assert_eq!(
+        tsv,
+        lines(&[
+            "key\tvalue\tcount\tfirst_seen\tlast_used\tscore",
+            "horse\t10\t2\t10\t10\t0",
+            "hamster\t20\t1\t20\t20\t0"
+        ])
+    );
+    assert_eq!(
+        json,
+        lines(&[
+            r#"[{"key":"hamster","value":1.0,"count":1,"first_seen":20,"last_used":20,"score":0.0},{"key":"horse","value":2.0,"count":2,"first_seen":10,"last_used":10,"score":0.0}]"#
+        ])
+    );
+}
+
+#[test]
+fn test_glob_match() {
+    assert!(glob_match(b"h*", b"horse"));
+    assert!(glob_match(b"*s*r", b"hamster"));
+    assert!(glob_match(b"h?rse", b"horse"));
+    assert!(glob_match("h?rse".as_bytes(), "hörse".as_bytes()));
+    assert!(glob_match(b"h?rse", b"h\xffrse"));
+    assert!(glob_match(b"*", b""));
+    assert!(glob_match(b"*a", b"*ba"));
+    assert!(glob_match(b"?a", b"?a"));
+    assert!(!glob_match(b"h?", b"horse"));
+    assert!(!glob_match(b"*z*", b"hamster"));
+}
+
+#[test]
+fn test_entry_management() {
+    let (dir, mut store) = test_store(
+        "manage",
+        HashMap::from([
+            (b"horse".to_vec(), entry(2, 10)),
+            (b"hamster".to_vec(), entry(1, 20)),
+            (b"cat".to_vec(), entry(5, 5)),
+            (b"dog".to_vec(), entry(3, 30)),
+        ]),
+    );
+    let removed = store.remove(&lines(&["dog"]), Some(b"h*r")).unwrap();
+    store.rename(b"horse", b"pony").unwrap();
+    store.set(b"cat", 1, &SavedValue::Count).unwrap();
+    let saved = get_lines_backup(store.path()).unwrap();
+    store.reset().unwrap();
+    let emptied = get_lines_backup(store.path()).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(removed, lines(&["dog", "hamster"]));
+    assert_eq!(saved.len(), 2);
+    assert_eq!(saved.get(&b"pony"[..]), Some(&entry(2, 10)));
+    assert_eq!(saved.get(&b"cat"[..]).unwrap().count, 1);
+    assert!(emptied.is_empty());
+}
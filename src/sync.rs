@@ -0,0 +1,465 @@
+use crate::cache::{from_value_name, value_name, write_atomically};
+use crate::error::{Error, Result};
+use crate::exchange::{tsv_field, tsv_unescape};
+use crate::{score, Cache, DeviceCounts, Entry, LinesBackup, SavedValue, CACHE_VERSION};
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Starts caches in the lines format, followed by their version
+pub(crate) const LINES_HEADER: &[u8] = b"baus\t";
+
+/// Lines git writes around the sides of a conflict, both of which are kept
+const CONFLICT_MARKERS: [&[u8]; 4] = [b"<<<<<<<", b"|||||||", b"=======", b">>>>>>>"];
+
+/// Name the saves of this machine are counted under, `BAUS_DEVICE` or else the host name
+pub fn get_device() -> String {
+    match std::env::var("BAUS_DEVICE") {
+        Ok(device) if !device.is_empty() => device,
+        _ => host_name().unwrap_or_else(|| "default".to_string()),
+    }
+}
+
+#[cfg(unix)]
+fn host_name() -> Option<String> {
+    let mut name = [0u8; 256];
+    // SAFETY: gethostname writes at most the given length into the buffer
+    if unsafe { libc::gethostname(name.as_mut_ptr() as *mut libc::c_char, name.len()) } != 0 {
+        return None;
+    }
+    let len = name.iter().position(|b| *b == 0)?;
+    Some(String::from_utf8_lossy(&name[..len]).into_owned()).filter(|name| !name.is_empty())
+}
+
+#[cfg(not(unix))]
+fn host_name() -> Option<String> {
+    std::env::var("COMPUTERNAME").ok()
+}
+
+fn earliest(a: i64, b: i64) -> i64 {
+    match (a, b) {
+        (0, t) | (t, 0) => t,
+        (a, b) => a.min(b),
+    }
+}
+
+/// Combines two copies of what one device counted for a line: counts only grow on their
+/// device so the highest is the latest, as is the last use. A copy started after the other
+/// was last used counts anew, the line having been removed in between, and replaces it
+fn merge_counts(entry: &mut Entry, other: &Entry) {
+    if other.first_seen > entry.last_used {
+        *entry = other.clone();
+        return;
+    }
+    if entry.first_seen > other.last_used {
+        return;
+    }
+    entry.count = entry.count.max(other.count);
+    entry.first_seen = earliest(entry.first_seen, other.first_seen);
+    entry.last_used = entry.last_used.max(other.last_used);
+}
+
+/// Last use of the rows a device removed the line at, if any did
+fn removed_at(devices: &BTreeMap<String, DeviceCounts>, key: &[u8]) -> Option<i64> {
+    devices
+        .values()
+        .filter_map(|counts| counts.removed.get(key))
+        .max()
+        .copied()
+}
+
+/// Whether a row still counts, having been used since the line was last removed
+fn still_counts(row: &Entry, removed_at: Option<i64>) -> bool {
+    removed_at.is_none_or(|time| row.last_used > time)
+}
+
+/// Merges the lines removed by another copy of a device in, keeping the latest removals
+fn merge_removed(removed: &mut HashMap<Vec<u8>, i64>, other: &HashMap<Vec<u8>, i64>) {
+    for (key, time) in other {
+        let merged = removed.entry(key.clone()).or_insert(*time);
+        *merged = (*merged).max(*time);
+    }
+}
+
+/// Merges another copy of the devices in, device by device and line by line, which gives
+/// the same result whatever the order copies are merged in and however often
+pub(crate) fn merge_devices(
+    devices: &mut BTreeMap<String, DeviceCounts>,
+    other: &BTreeMap<String, DeviceCounts>,
+) {
+    for (device, counts) in other {
+        let merged = devices.entry(device.clone()).or_default();
+        merged.last_event = merged.last_event.max(counts.last_event);
+        merged.trimmed = merged.trimmed.max(counts.trimmed);
+        merge_removed(&mut merged.removed, &counts.removed);
+        for (key, entry) in &counts.entries {
+            match merged.entries.get_mut(key) {
+                Some(merged) => merge_counts(merged, entry),
+                None => {
+                    merged.entries.insert(key.clone(), entry.clone());
+                }
+            }
+        }
+    }
+}
+
+/// Adds up what every device counted for each line since it was last removed,
+/// scored at its last use
+pub(crate) fn total_entries(
+    devices: &BTreeMap<String, DeviceCounts>,
+    saved_value: &SavedValue,
+) -> LinesBackup {
+    let mut entries = LinesBackup::new();
+    for counts in devices.values() {
+        for (key, entry) in &counts.entries {
+            if !still_counts(entry, removed_at(devices, key)) {
+                continue;
+            }
+            let total = entries.entry(key.clone()).or_default();
+            total.count += entry.count;
+            total.first_seen = earliest(total.first_seen, entry.first_seen);
+            total.last_used = total.last_used.max(entry.last_used);
+        }
+    }
+    for entry in entries.values_mut() {
+        entry.score = score(saved_value, entry, entry.last_used);
+    }
+    entries
+}
+
+/// Rows of this device: whatever the entries hold beyond what the other devices counted,
+/// kept when it saved the line itself or used it last. A row removed since it was last used
+/// starts over from the first use after the removal
+fn local_entries(cache: &Cache, device: &str) -> LinesBackup {
+    let own = cache.devices.get(device);
+    cache
+        .entries
+        .iter()
+        .filter_map(|(key, entry)| {
+            let removed_at = removed_at(&cache.devices, key);
+            let others: Vec<&Entry> = cache
+                .devices
+                .iter()
+                .filter(|(name, _)| *name != device)
+                .filter_map(|(_, counts)| counts.entries.get(key))
+                .filter(|row| still_counts(row, removed_at))
+                .collect();
+            let count = entry.count - others.iter().map(|other| other.count).sum::<i64>();
+            let last_used = others.iter().map(|other| other.last_used).max();
+            if others.is_empty() || count > 0 || last_used < Some(entry.last_used) {
+                let first_seen = match own
+                    .and_then(|own| own.entries.get(key))
+                    .filter(|row| still_counts(row, removed_at))
+                {
+                    Some(row) => row.first_seen,
+                    None => {
+                        removed_at.map_or(entry.first_seen, |time| entry.first_seen.max(time + 1))
+                    }
+                };
+                let local = Entry {
+                    count: count.max(0),
+                    first_seen,
+                    last_used: entry.last_used,
+                    ..Entry::new()
+                };
+                Some((key.clone(), local))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// What each device counted, the rows of this one brought up to date with the entries,
+/// lines counted by a device but gone from the entries being recorded as removed by this one.
+/// Caches without devices count as this one alone
+pub(crate) fn devices_of(cache: &Cache, device: &str) -> BTreeMap<String, DeviceCounts> {
+    let mut devices = cache.devices.clone();
+    let mut removed = devices
+        .get(device)
+        .map(|counts| counts.removed.clone())
+        .unwrap_or_default();
+    for (key, row) in cache.devices.values().flat_map(|counts| &counts.entries) {
+        if !cache.entries.contains_key(key) && still_counts(row, removed_at(&cache.devices, key)) {
+            merge_removed(&mut removed, &HashMap::from([(key.clone(), row.last_used)]));
+        }
+    }
+    devices.insert(
+        device.to_string(),
+        DeviceCounts {
+            entries: local_entries(cache, device),
+            last_event: cache.last_event,
+            trimmed: cache.trimmed,
+            removed,
+        },
+    );
+    devices
+}
+
+fn number<T: std::str::FromStr>(field: &[u8]) -> Option<T> {
+    std::str::from_utf8(field).ok()?.parse().ok()
+}
+
+/// Parses a cache in the lines format, `None` when a line is not one it writes.
+/// Rows of the same device and line are merged and conflict markers skipped, so a file
+/// git left both sides of a conflict in loads as the merge of both
+pub(crate) fn parse(path: &Path, contents: &[u8], device: &str) -> Result<Option<Cache>> {
+    let mut lines = contents.split(|b| *b == b'\n');
+    let version = match lines
+        .next()
+        .and_then(|line| line.strip_prefix(LINES_HEADER))
+        .and_then(number::<i64>)
+    {
+        Some(version) => version,
+        None => return Ok(None),
+    };
+    if version > CACHE_VERSION {
+        return Err(Error::UnsupportedVersion {
+            path: path.to_path_buf(),
+            version,
+        });
+    }
+    let mut cache = Cache::default();
+    for line in lines {
+        if line.is_empty() || CONFLICT_MARKERS.iter().any(|m| line.starts_with(m)) {
+            continue;
+        }
+        let fields: Vec<Vec<u8>> = line.split(|b| *b == b'\t').map(tsv_unescape).collect();
+        let parsed = match (&fields[0][..], &fields[1..]) {
+            (b"mode", [mode]) => from_value_name(mode).map(|mode| {
+                cache.mode.get_or_insert(mode);
+            }),
+            (b"pin", [pin, key]) => from_value_name(pin).map(|pin| {
+                cache.pinned.entry(key.clone()).or_insert(pin);
+            }),
+            (b"block", [key]) => {
+                cache.blocked.insert(key.clone());
+                Some(())
+            }
+            (b"device", [name, last_event, trimmed]) => number(last_event)
+                .zip(number(trimmed))
+                .map(|(last_event, trimmed)| {
+                    let name = String::from_utf8_lossy(name).into_owned();
+                    let counts = cache.devices.entry(name).or_default();
+                    counts.last_event = counts.last_event.max(last_event);
+                    counts.trimmed = counts.trimmed.max(trimmed);
+                }),
+            (b"remove", [name, key, time]) => number(time).map(|time| {
+                let name = String::from_utf8_lossy(name).into_owned();
+                let counts = cache.devices.entry(name).or_default();
+                merge_removed(&mut counts.removed, &HashMap::from([(key.clone(), time)]));
+            }),
+            (b"entry", [name, key, count, first_seen, last_used]) => (|| {
+                let entry = Entry {
+                    count: number(count)?,
+                    first_seen: number(first_seen)?,
+                    last_used: number(last_used)?,
+                    ..Entry::new()
+                };
+                let name = String::from_utf8_lossy(name).into_owned();
+                let counts = cache.devices.entry(name).or_default();
+                match counts.entries.get_mut(key) {
+                    Some(counted) => merge_counts(counted, &entry),
+                    None => {
+                        counts.entries.insert(key.clone(), entry);
+                    }
+                }
+                Some(())
+            })(),
+            _ => None,
+        };
+        if parsed.is_none() {
+            return Ok(None);
+        }
+    }
+    let saved_value = cache.mode.clone().unwrap_or_default();
+    cache.entries = total_entries(&cache.devices, &saved_value);
+    if let Some(counts) = cache.devices.get(device) {
+        cache.last_event = counts.last_event;
+        cache.trimmed = counts.trimmed;
+    }
+    // the saves of other devices are in their logs, not this one
+    cache.unlogged = cache
+        .devices
+        .iter()
+        .any(|(name, counts)| name != device && !counts.entries.is_empty());
+    Ok(Some(cache))
+}
+
+/// Writes the cache as sorted lines, one row per device and line so that saves on different
+/// machines change different lines.
+/// Rows of lines no longer in the entries are dropped, whichever device counted them,
+/// the lines removed by each device being kept
+pub(crate) fn write(cache_file_path: &Path, cache: &Cache, device: &str) -> Result<()> {
+    let row = |fields: &[&[u8]]| -> Vec<u8> {
+        let fields: Vec<Vec<u8>> = fields.iter().map(|field| tsv_field(field)).collect();
+        fields.join(&b'\t')
+    };
+    let mut lines = Vec::new();
+    if let Some(mode) = &cache.mode {
+        lines.push(row(&[b"mode", &value_name(mode)]));
+    }
+    for (key, pin) in &cache.pinned {
+        lines.push(row(&[b"pin", &value_name(pin), key]));
+    }
+    for key in &cache.blocked {
+        lines.push(row(&[b"block", key]));
+    }
+    for (name, counts) in devices_of(cache, device) {
+        lines.push(row(&[
+            b"device",
+            name.as_bytes(),
+            counts.last_event.to_string().as_bytes(),
+            counts.trimmed.to_string().as_bytes(),
+        ]));
+        for (key, time) in &counts.removed {
+            lines.push(row(&[
+                b"remove",
+                name.as_bytes(),
+                key,
+                time.to_string().as_bytes(),
+            ]));
+        }
+        for (key, entry) in &counts.entries {
+            if !cache.entries.contains_key(key) {
+                continue;
+            }
+            lines.push(row(&[
+                b"entry",
+                name.as_bytes(),
+                key,
+                entry.count.to_string().as_bytes(),
+                entry.first_seen.to_string().as_bytes(),
+                entry.last_used.to_string().as_bytes(),
+            ]));
+        }
+    }
+    lines.sort();
+    let mut contents = [LINES_HEADER, CACHE_VERSION.to_string().as_bytes(), b"\n"].concat();
+    for line in lines {
+        contents.extend(line);
+        contents.push(b'\n');
+    }
+    write_atomically(cache_file_path, &contents)
+}
+
+#[test]
+fn test_lines_format() {
+    let dir = crate::cache::test_dir("sync");
+    let cache_file_path = dir.join("animals");
+    let mut cache = Cache {
+        mode: Some(SavedValue::Count),
+        last_event: 3,
+        ..Cache::default()
+    };
+    cache.entries.insert(b"horse".to_vec(), crate::entry(3, 10));
+    cache
+        .entries
+        .insert(b"a\tb\n".to_vec(), crate::entry(1, 20));
+    cache.pinned.insert(b"cow".to_vec(), crate::Pin::Top);
+    cache.blocked.insert(b"wolf".to_vec());
+    std::fs::create_dir_all(&dir).unwrap();
+    write(&cache_file_path, &cache, "laptop").unwrap();
+    let laptop = std::fs::read(&cache_file_path).unwrap();
+    let mut loaded = parse(&cache_file_path, &laptop, "laptop").unwrap().unwrap();
+    // the desktop saves horse twice and removes the line of the laptop
+    let mut desktop = parse(&cache_file_path, &laptop, "desktop")
+        .unwrap()
+        .unwrap();
+    assert_eq!(desktop.last_event, 0);
+    let horse = desktop.entries.get_mut(&b"horse"[..]).unwrap();
+    horse.count += 2;
+    horse.last_used = 30;
+    desktop.entries.remove(&b"a\tb\n"[..]);
+    write(&cache_file_path, &desktop, "desktop").unwrap();
+    let desktop = std::fs::read(&cache_file_path).unwrap();
+    // meanwhile the laptop saves horse once more
+    loaded.entries.get_mut(&b"horse"[..]).unwrap().count += 1;
+    write(&cache_file_path, &loaded, "laptop").unwrap();
+    let laptop = std::fs::read(&cache_file_path).unwrap();
+    // git keeps both sides when the laptop also saved horse, the header being the same
+    let body = |contents: &[u8]| contents[b"baus\t3\n".len()..].to_vec();
+    let conflicted = [
+        &b"baus\t3\n<<<<<<< HEAD\n"[..],
+        &body(&laptop),
+        b"=======\n",
+        &body(&desktop),
+        b">>>>>>> desktop\n",
+    ]
+    .concat();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(
+        String::from_utf8_lossy(&desktop),
+        "baus\t3\nblock\twolf\ndevice\tdesktop\t0\t0\ndevice\tlaptop\t3\t0\n\
+         entry\tdesktop\thorse\t2\t10\t30\nentry\tlaptop\thorse\t3\t10\t10\nmode\tcount\npin\ttop\tcow\n\
+         remove\tdesktop\ta\\tb\\n\t20\n"
+    );
+    let merged = parse(&cache_file_path, &conflicted, "laptop")
+        .unwrap()
+        .unwrap();
+    let horse = merged.entries.get(&b"horse"[..]).unwrap();
+    assert_eq!(
+        (horse.count, horse.first_seen, horse.last_used),
+        (6, 10, 30)
+    );
+    assert_eq!(horse.score, 6.0);
+    // the laptop side still counts the line the desktop removed since it was last used
+    assert!(!merged.entries.contains_key(&b"a\tb\n"[..]));
+    assert_eq!(merged.last_event, 3);
+    assert_eq!(merged.pinned, cache.pinned);
+    assert_eq!(merged.blocked, cache.blocked);
+    assert_eq!(
+        parse(&cache_file_path, b"baus\t3\nentry\tx\n", "x").unwrap(),
+        None
+    );
+}
+
+#[test]
+fn test_merge_devices() {
+    let devices = |rows: &[(&str, &[u8], i64, i64)]| {
+        let mut devices = BTreeMap::<String, DeviceCounts>::new();
+        for (device, key, count, last_used) in rows {
+            let counts = devices.entry(device.to_string()).or_default();
+            let entry = Entry {
+                first_seen: 1,
+                ..crate::entry(*count, *last_used)
+            };
+            counts.entries.insert(key.to_vec(), entry);
+        }
+        devices
+    };
+    let a = devices(&[("laptop", b"ls", 3, 10), ("desktop", b"ls", 1, 5)]);
+    let b = devices(&[
+        ("laptop", b"ls", 2, 8),
+        ("desktop", b"ls", 4, 20),
+        ("phone", b"cd", 1, 7),
+    ]);
+    let mut ab = a.clone();
+    merge_devices(&mut ab, &b);
+    let mut ba = b.clone();
+    merge_devices(&mut ba, &a);
+    assert_eq!(ab, ba);
+    let mut again = ab.clone();
+    merge_devices(&mut again, &b);
+    assert_eq!(again, ab);
+    let entries = total_entries(&ab, &SavedValue::Count);
+    let ls = entries.get(&b"ls"[..]).unwrap();
+    assert_eq!((ls.count, ls.first_seen, ls.last_used), (7, 1, 20));
+    assert_eq!(entries.get(&b"cd"[..]).unwrap().count, 1);
+    // the desktop removes ls after its last use, then the laptop saves it anew
+    let mut removed = ab.clone();
+    let desktop = removed.get_mut("desktop").unwrap();
+    desktop.removed.insert(b"ls".to_vec(), 20);
+    assert!(!total_entries(&removed, &SavedValue::Count).contains_key(&b"ls"[..]));
+    let mut restarted = BTreeMap::<String, DeviceCounts>::new();
+    let laptop = restarted.entry("laptop".to_string()).or_default();
+    laptop.entries.insert(b"ls".to_vec(), crate::entry(1, 30));
+    merge_devices(&mut removed, &restarted);
+    let entries = total_entries(&removed, &SavedValue::Count);
+    let ls = entries.get(&b"ls"[..]).unwrap();
+    assert_eq!((ls.count, ls.first_seen, ls.last_used), (1, 30, 30));
+    // copies from before the removal bring nothing back
+    merge_devices(&mut removed, &a);
+    merge_devices(&mut removed, &b);
+    assert_eq!(total_entries(&removed, &SavedValue::Count), entries);
+}